@@ -0,0 +1,22 @@
+use std::env;
+use std::process::Command;
+
+/// Exposes the git commit and rustc version to `airthing_build_info`, as `unknown` when they can't be found.
+fn main() {
+    let git_commit = output("git", &["rev-parse", "--short", "HEAD"]);
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = output(&rustc, &["--version"]);
+
+    println!("cargo:rustc-env=AIRTHING_GIT_COMMIT={}", git_commit.unwrap_or_else(|| String::from("unknown")));
+    println!("cargo:rustc-env=AIRTHING_RUSTC_VERSION={}", rustc_version.unwrap_or_else(|| String::from("unknown")));
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok().map(|stdout| stdout.trim().to_string())
+}
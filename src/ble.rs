@@ -0,0 +1,536 @@
+//! Bluetooth LE devices found through btleplug.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::future::{self, Future};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use btleplug::api::{Central, CharPropFlags, Characteristic, Manager as _, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use futures::StreamExt;
+use log::{debug, info, log_enabled, trace, warn, Level};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time;
+use uuid::Uuid;
+
+use crate::cli::AdapterSelection;
+use crate::config::RetryConfig;
+use crate::sensor::{
+    self, SensorModel, SensorValues, BATTERY_LEVEL_CHARACTERISTIC_UUID, FIRMWARE_REVISION_CHARACTERISTIC_UUID,
+    MODEL_NUMBER_CHARACTERISTIC_UUID,
+};
+use crate::source::{Advertisement, DeviceInfo, FailureReason, QueryError, Scan, SensorSource, Timeout, WithReason};
+
+/// starts scanning on the selected adapter, or on all of them, for devices advertising a serial
+/// under one of `company_ids`, connecting to, discovering and reading from devices each give up after `timeout`.
+/// Starting is retried as `start_retry` says, adapters are often not up yet right after boot
+pub async fn new_ble_source(
+    selection: Option<&AdapterSelection>,
+    timeout: Duration,
+    company_ids: Vec<u16>,
+    start_retry: &RetryConfig,
+) -> Result<Box<dyn SensorSource>> {
+    let adapters = start_scanning_with_retries(selection, start_retry).await?;
+    let mut adapter_names = Vec::new();
+    for (index, adapter) in adapters.iter().enumerate() {
+        let name = match adapter.adapter_info().await {
+            Ok(info) => adapter_name(&info),
+            Err(err) => {
+                debug!("Could not get the name of adapter {}: {:?}", index, err);
+                index.to_string()
+            }
+        };
+        adapter_names.push(name);
+    }
+    let (pushed, received) = mpsc::unbounded_channel();
+    Ok(Box::new(BleSource {
+        adapters,
+        adapter_names,
+        timeout,
+        company_ids,
+        peripherals: RefCell::new(HashMap::new()),
+        device_infos: RefCell::new(HashMap::new()),
+        subscriptions: RefCell::new(HashMap::new()),
+        pushed,
+        received: Mutex::new(received),
+    }))
+}
+
+struct BleSource {
+    adapters: Vec<Adapter>,
+    /// by adapter index, as advertisements report them
+    adapter_names: Vec<String>,
+    /// per device operation
+    timeout: Duration,
+    company_ids: Vec<u16>,
+    /// as of the latest scan
+    peripherals: RefCell<HashMap<u32, Peripheral>>,
+    /// read once per device, they only change with a firmware update
+    device_infos: RefCell<HashMap<u32, DeviceInfo>>,
+    subscriptions: RefCell<HashMap<u32, Subscription>>,
+    /// values pushed by subscribed devices, handed out by `next_notification`
+    pushed: UnboundedSender<(u32, SensorValues)>,
+    received: Mutex<UnboundedReceiver<(u32, SensorValues)>>,
+}
+
+/// A device kept connected, pushing its values. Dropping it stops forwarding them.
+struct Subscription {
+    peripheral: Peripheral,
+    forwarder: JoinHandle<()>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+    }
+}
+
+impl BleSource {
+    fn peripheral(&self, serial: u32) -> Result<Peripheral> {
+        self.peripherals.borrow().get(&serial).cloned()
+            .ok_or_else(|| anyhow!("peripheral {} is not in range", serial))
+    }
+
+    /// devices that disconnected are read again, and subscribed to anew after the next read
+    async fn drop_lost_subscriptions(&self) {
+        let subscribed: Vec<(u32, Peripheral)> = self.subscriptions.borrow().iter()
+            .map(|(serial, subscription)| (*serial, subscription.peripheral.clone()))
+            .collect();
+        for (serial, peripheral) in subscribed {
+            if !matches!(peripheral.is_connected().await, Ok(true)) {
+                info!("peripheral {} stopped pushing values, polling it again", serial);
+                self.subscriptions.borrow_mut().remove(&serial);
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl SensorSource for BleSource {
+    async fn scan(&self) -> Result<Scan> {
+        self.drop_lost_subscriptions().await;
+        let mut scan = Scan::default();
+        // a device in range of several adapters is reported by each of them, only queried once
+        let mut found = HashMap::new();
+        for (adapter, adapter_name) in self.adapters.iter().zip(self.adapter_names.iter()) {
+            let peripherals = match adapter.peripherals().await {
+                Ok(peripherals) => peripherals,
+                Err(err) => {
+                    warn!("Could not get peripherals: {:?}", err);
+                    continue;
+                }
+            };
+
+            if peripherals.is_empty() {
+                debug!("No peripheral devices found, skipping");
+                continue;
+            }
+
+            // All peripheral devices in range.
+            trace!("discovered {} peripherals", peripherals.len());
+            scan.peripherals += peripherals.len();
+            for peripheral in peripherals {
+                let properties = match peripheral.properties().await {
+                    Ok(Some(properties)) => properties,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        debug!("Failed to read properties from peripheral, skipping: {:?}", err);
+                        continue;
+                    }
+                };
+
+                if log_enabled!(Level::Trace) {
+                    trace!("peripheral {} advertises manufacturer data [{}]", properties.address,
+                        sensor::manufacturer_data_hex(&properties.manufacturer_data));
+                }
+                if let Some(serial) = sensor::parse_serial(properties.manufacturer_data, &self.company_ids) {
+                    let advertisement = Advertisement { serial, rssi: properties.rssi, adapter: Some(adapter_name.clone()) };
+                    keep_strongest(&mut found, advertisement, peripheral);
+                }
+            }
+        }
+
+        let mut peripherals = HashMap::new();
+        for (serial, (advertisement, peripheral)) in found {
+            scan.advertisements.push(advertisement);
+            peripherals.insert(serial, peripheral);
+        }
+        *self.peripherals.borrow_mut() = peripherals;
+        Ok(scan)
+    }
+
+    fn adapters(&self) -> usize {
+        self.adapters.len()
+    }
+
+    async fn read(&self, serial: u32) -> Result<SensorValues, QueryError> {
+        let peripheral = self.peripheral(serial).reason(FailureReason::Connect)?;
+        let with_device_info = !self.device_infos.borrow().contains_key(&serial);
+        let (values, device_info) = read_peripheral(&peripheral, with_device_info, self.timeout).await?;
+        if let Some(device_info) = device_info {
+            self.device_infos.borrow_mut().insert(serial, device_info);
+        }
+        Ok(values)
+    }
+
+    async fn read_raw(&self, serial: u32) -> Result<(SensorModel, Vec<u8>)> {
+        let peripheral = self.peripheral(serial).context(FailureReason::Connect)?;
+        let model = connect(&peripheral, self.timeout).await?;
+        let payload = RefCell::new(Vec::new());
+        read_characteristics(&peripheral, payload_reads(model, &payload), self.timeout).await?;
+        Ok((model, payload.into_inner()))
+    }
+
+    async fn device_info(&self, serial: u32) -> Result<DeviceInfo> {
+        if let Some(device_info) = self.device_infos.borrow().get(&serial) {
+            return Ok(device_info.clone());
+        }
+
+        // read along with the values, unless that failed
+        let peripheral = self.peripheral(serial)?;
+        let (mut model, mut firmware) = (Ok(None), Ok(None));
+        read_characteristics(&peripheral, device_info_reads(&mut model, &mut firmware), self.timeout).await?;
+        let device_info = DeviceInfo { model: model?, firmware: firmware? };
+        self.device_infos.borrow_mut().insert(serial, device_info.clone());
+        Ok(device_info)
+    }
+
+    async fn disconnect(&self, serial: u32) -> Result<()> {
+        self.subscriptions.borrow_mut().remove(&serial);
+        with_timeout(self.timeout, self.peripheral(serial)?.disconnect()).await
+    }
+
+    async fn subscribe(&self, serial: u32) -> Result<bool> {
+        if self.subscriptions.borrow().contains_key(&serial) {
+            return Ok(true);
+        }
+        let peripheral = self.peripheral(serial)?;
+        let chars = peripheral.characteristics();
+        let model = sensor_model(&chars).ok_or_else(|| anyhow!("Failed to find correct characteristic"))?;
+        // values spread over several characteristics do not arrive together
+        if model.value_characteristics().len() > 1 {
+            return Ok(false);
+        }
+        let char = chars.into_iter().find(|c| c.uuid == model.characteristic_uuid())
+            .ok_or_else(|| anyhow!("Failed to find correct characteristic"))?;
+        if !char.properties.intersects(CharPropFlags::NOTIFY | CharPropFlags::INDICATE) {
+            return Ok(false);
+        }
+
+        let mut notifications = with_timeout(self.timeout, peripheral.notifications()).await?;
+        with_timeout(self.timeout, peripheral.subscribe(&char)).await?;
+        let pushed = self.pushed.clone();
+        let forwarder = tokio::spawn(async move {
+            while let Some(notification) = notifications.next().await {
+                if notification.uuid != char.uuid {
+                    continue;
+                }
+                match SensorValues::from_vec(model, notification.value) {
+                    Ok(values) => {
+                        if pushed.send((serial, values)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => debug!("peripheral {} pushed values we can not parse: {}", serial, err),
+                }
+            }
+        });
+        self.subscriptions.borrow_mut().insert(serial, Subscription { peripheral, forwarder });
+        Ok(true)
+    }
+
+    fn subscribed(&self) -> Vec<u32> {
+        self.subscriptions.borrow().keys().copied().collect()
+    }
+
+    async fn next_notification(&self) -> (u32, SensorValues) {
+        match self.received.lock().await.recv().await {
+            Some(notification) => notification,
+            // never closes, we hold a sender ourselves
+            None => future::pending().await,
+        }
+    }
+
+    async fn restart_scan(&self) -> Result<()> {
+        for adapter in self.adapters.iter() {
+            if let Err(err) = adapter.stop_scan().await {
+                debug!("Failed to stop scan, starting it anyway: {:?}", err);
+            }
+            adapter.start_scan(scan_filter()).await.context("failed to restart scan")?;
+        }
+        Ok(())
+    }
+
+    async fn disconnect_all(&self) {
+        self.subscriptions.borrow_mut().clear();
+        for adapter in self.adapters.iter() {
+            let peripherals = match adapter.peripherals().await {
+                Ok(peripherals) => peripherals,
+                Err(err) => {
+                    debug!("Could not get peripherals to disconnect: {:?}", err);
+                    continue;
+                }
+            };
+
+            for peripheral in peripherals.iter() {
+                if let Ok(true) = peripheral.is_connected().await {
+                    if let Err(err) = peripheral.disconnect().await {
+                        debug!("Failed to disconnect peripheral {}: {:?}", peripheral.address(), err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// keeps the peripheral a device is heard best through, one without signal strength only if there is no other
+fn keep_strongest<P>(found: &mut HashMap<u32, (Advertisement, P)>, advertisement: Advertisement, peripheral: P) {
+    match found.get(&advertisement.serial) {
+        Some((current, _)) if current.rssi >= advertisement.rssi => {
+            trace!("peripheral {} also seen with rssi {:?}, keeping {:?}",
+                advertisement.serial, advertisement.rssi, current.rssi);
+        }
+        _ => {
+            found.insert(advertisement.serial, (advertisement, peripheral));
+        }
+    }
+}
+
+/// What to do with the value of a characteristic, or with the error reading it. An error returned ends the reads.
+type CharacteristicRead<'a> = (Uuid, Box<dyn FnMut(Result<Vec<u8>>) -> Result<(), QueryError> + 'a>);
+
+/// reads the values, the battery level and, `with_device_info`, model and firmware, all in one connection.
+/// The samples logged while disconnected are not among them, their characteristic and layout are unpublished
+async fn read_peripheral(
+    peripheral: &Peripheral,
+    with_device_info: bool,
+    timeout: Duration,
+) -> Result<(SensorValues, Option<DeviceInfo>), QueryError> {
+    let sensor_model = connect(peripheral, timeout).await?;
+
+    let payload = RefCell::new(Vec::new());
+    let mut battery = None;
+    let (mut model, mut firmware) = (Ok(None), Ok(None));
+    let mut reads = payload_reads(sensor_model, &payload);
+    // best effort, not every model exposes the battery service
+    reads.push((BATTERY_LEVEL_CHARACTERISTIC_UUID, Box::new(|read: Result<Vec<u8>>| {
+        match read {
+            Ok(level) => battery = level.first().copied(),
+            Err(err) => debug!("Failed to read battery level: {:?}", err),
+        }
+        Ok(())
+    })));
+    if with_device_info {
+        reads.extend(device_info_reads(&mut model, &mut firmware));
+    }
+    read_characteristics(peripheral, reads, timeout).await?;
+
+    let mut values = SensorValues::from_vec(sensor_model, payload.into_inner()).reason(FailureReason::Parse)?;
+    values.battery = battery;
+    let device_info = match (with_device_info, model, firmware) {
+        (false, _, _) => None,
+        (true, Ok(model), Ok(firmware)) => Some(DeviceInfo { model, firmware }),
+        (true, Err(err), _) | (true, _, Err(err)) => {
+            debug!("Failed to read device info, reading it again later: {:?}", err);
+            None
+        }
+    };
+    Ok((values, device_info))
+}
+
+/// connects unless connected already and tells the model by the sensor values characteristics exposed
+async fn connect(peripheral: &Peripheral, timeout: Duration) -> Result<SensorModel, QueryError> {
+    let is_connected = peripheral.is_connected().await.reason(FailureReason::Connect)?;
+    if !is_connected {
+        with_timeout(timeout, peripheral.connect()).await.reason(FailureReason::Connect)?
+    }
+
+    // discover services and characteristics
+    with_timeout(timeout, peripheral.discover_services()).await.reason(FailureReason::Discover)?;
+
+    let chars = peripheral.characteristics();
+    let uuids = || chars.iter().map(|c| c.uuid.to_string()).collect::<Vec<String>>().join(", ");
+    let model = match sensor_model(&chars) {
+        Some(model) => model,
+        None => {
+            return Err(anyhow!("none of {} is a sensor values characteristic", uuids()))
+                .reason(FailureReason::Characteristic)
+        }
+    };
+    let missing: Vec<String> = model.value_characteristics().into_iter()
+        .filter(|uuid| !chars.iter().any(|c| c.uuid == *uuid))
+        .map(|uuid| uuid.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!("{:?} values characteristics {} are not among {}", model, missing.join(", "), uuids()))
+            .reason(FailureReason::Characteristic);
+    }
+    Ok(model)
+}
+
+fn sensor_model(chars: &BTreeSet<Characteristic>) -> Option<SensorModel> {
+    SensorModel::from_characteristics(&chars.iter().map(|c| c.uuid).collect::<Vec<Uuid>>())
+}
+
+/// the model's values characteristics, put together into its payload
+fn payload_reads(model: SensorModel, payload: &RefCell<Vec<u8>>) -> Vec<CharacteristicRead<'_>> {
+    model.value_characteristics().into_iter()
+        .map(|uuid| -> CharacteristicRead {
+            (uuid, Box::new(move |read: Result<Vec<u8>>| {
+                payload.borrow_mut().extend(read.reason(FailureReason::Read)?);
+                Ok(())
+            }))
+        })
+        .collect()
+}
+
+/// reads `reads` in order over the connection `connect` set up, skipping characteristics the device does not expose
+async fn read_characteristics(
+    peripheral: &Peripheral,
+    reads: Vec<CharacteristicRead<'_>>,
+    timeout: Duration,
+) -> Result<(), QueryError> {
+    let chars = peripheral.characteristics();
+    for (uuid, mut handle) in reads {
+        match chars.iter().find(|c| c.uuid == uuid) {
+            Some(char) => handle(with_timeout(timeout, peripheral.read(char)).await)?,
+            None => trace!("characteristic {} is not exposed, skipping", uuid),
+        }
+    }
+    Ok(())
+}
+
+/// model number and firmware revision, left `None` if the device does not expose them
+fn device_info_reads<'a>(
+    model: &'a mut Result<Option<String>>,
+    firmware: &'a mut Result<Option<String>>,
+) -> Vec<CharacteristicRead<'a>> {
+    vec![
+        (MODEL_NUMBER_CHARACTERISTIC_UUID, Box::new(move |read: Result<Vec<u8>>| {
+            *model = read.map(|data| Some(characteristic_string(&data)));
+            Ok(())
+        })),
+        (FIRMWARE_REVISION_CHARACTERISTIC_UUID, Box::new(move |read: Result<Vec<u8>>| {
+            *firmware = read.map(|data| Some(characteristic_string(&data)));
+            Ok(())
+        })),
+    ]
+}
+
+fn characteristic_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).trim_end_matches('\0').to_string()
+}
+
+/// fails with `Timeout` if the device does not answer in time
+async fn with_timeout<T>(timeout: Duration, operation: impl Future<Output = btleplug::Result<T>>) -> Result<T> {
+    match time::timeout(timeout, operation).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Timeout(timeout).into()),
+    }
+}
+
+async fn start_scanning_with_retries(selection: Option<&AdapterSelection>, retry: &RetryConfig) -> Result<Vec<Adapter>> {
+    let mut attempt = 1;
+    loop {
+        match start_scanning(selection).await {
+            Ok(adapters) => return Ok(adapters),
+            Err(err) if attempt >= retry.attempts => return Err(err),
+            Err(err) => {
+                let delay = retry.backoff(attempt);
+                warn!("attempt {} to start scanning failed, retrying in {:?}: {:#}", attempt, delay, err);
+                time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn start_scanning(selection: Option<&AdapterSelection>) -> Result<Vec<Adapter>> {
+    let manager = Manager::new().await?;
+    let mut adapter_list = manager.adapters().await?;
+    if adapter_list.is_empty() {
+        return Err(anyhow!("no Bluetooth adapters found"));
+    }
+
+    if let Some(selection) = selection {
+        let mut names = Vec::new();
+        for adapter in adapter_list.iter() {
+            names.push(adapter_name(&adapter.adapter_info().await?));
+        }
+        let index = find_adapter(&names, selection)
+            .ok_or_else(|| anyhow!("adapter not found, available adapters: {}", names.join(", ")))?;
+        info!("Using adapter {}", names[index]);
+        adapter_list = vec![adapter_list.swap_remove(index)];
+    }
+
+    for (started, adapter) in adapter_list.iter().enumerate() {
+        info!("Starting scan...");
+        if let Err(err) = adapter.start_scan(scan_filter()).await {
+            // so the next attempt starts them all afresh
+            for adapter in adapter_list[..started].iter() {
+                if let Err(err) = adapter.stop_scan().await {
+                    debug!("Failed to stop scan: {:?}", err);
+                }
+            }
+            return Err(err).context("failed to start scan");
+        }
+    }
+
+    Ok(adapter_list)
+}
+
+/// only devices advertising a sensor service are reported
+fn scan_filter() -> ScanFilter {
+    ScanFilter { services: SensorModel::ALL.iter().map(|model| model.service_uuid()).collect() }
+}
+
+/// bluez reports adapters as `hci0 (usb:v1D6Bp0246d0537)`, the name is the first word
+fn adapter_name(adapter_info: &str) -> String {
+    adapter_info.split_whitespace().next().unwrap_or_default().to_string()
+}
+
+fn find_adapter(names: &[String], selection: &AdapterSelection) -> Option<usize> {
+    match selection {
+        AdapterSelection::Name(name) => names.iter().position(|candidate| candidate == name),
+        AdapterSelection::Index(index) if *index < names.len() => Some(*index),
+        AdapterSelection::Index(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{adapter_name, find_adapter, keep_strongest};
+    use crate::cli::AdapterSelection;
+    use crate::source::Advertisement;
+
+    #[test]
+    fn device_seen_by_several_adapters_is_kept_once_with_the_best_signal() {
+        let mut found = HashMap::new();
+        let seen = |rssi| Advertisement { serial: 2930000001, rssi, adapter: None };
+
+        keep_strongest(&mut found, seen(None), "hci0");
+        keep_strongest(&mut found, seen(Some(-80)), "hci1");
+        keep_strongest(&mut found, seen(Some(-60)), "hci2");
+        keep_strongest(&mut found, seen(Some(-70)), "hci3");
+
+        assert_eq!(found.len(), 1);
+        let (advertisement, adapter) = &found[&2930000001];
+        assert_eq!((advertisement.rssi, *adapter), (Some(-60), "hci2"));
+    }
+
+    #[test]
+    fn adapter_is_selected_by_name_or_index() {
+        let names = vec![adapter_name("hci0 (usb:v1D6Bp0246d0537)"), adapter_name("hci1 (usb:v0A12p0001d8891)")];
+
+        assert_eq!(find_adapter(&names, &AdapterSelection::Name(String::from("hci1"))), Some(1));
+        assert_eq!(find_adapter(&names, &AdapterSelection::Name(String::from("hci2"))), None);
+        assert_eq!(find_adapter(&names, &AdapterSelection::Index(0)), Some(0));
+        assert_eq!(find_adapter(&names, &AdapterSelection::Index(2)), None);
+    }
+}
@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use log::LevelFilter;
+
+use crate::logging::{LogFile, LogFormat};
+
+pub struct Args {
+    pub config_path: String,
+    pub discover_all: bool,
+    /// overrides `scan_interval` from the config
+    pub scan_interval: Option<Duration>,
+    /// runs the first pass right away instead of after one `scan_interval`
+    pub poll_on_start: bool,
+    /// where learned query intervals are kept across restarts
+    pub state_file: Option<String>,
+    /// scan on this adapter only instead of all of them
+    pub adapter: Option<AdapterSelection>,
+    /// address the http endpoints are served on
+    pub listen: SocketAddr,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub log_file: Option<LogFile>,
+    /// synthesize readings instead of scanning, from a fixture if set
+    pub mock: bool,
+    pub mock_fixture: Option<String>,
+    /// print the raw sensor payload of every device in range and exit
+    pub dump_raw: bool,
+    /// validate the config, print what it configures and exit
+    pub check_config: bool,
+}
+
+pub enum AdapterSelection {
+    /// as reported by the platform, e.g. `hci1`
+    Name(String),
+    Index(usize),
+}
+
+/// Exports readings of Airthings devices nearby as Prometheus metrics.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// devices and settings
+    #[arg(long = "config", env = "AIRTHING_CONFIG", default_value = "devices.toml", value_name = "PATH")]
+    config_path: String,
+    /// also export devices missing from the config
+    #[arg(long)]
+    discover_all: bool,
+    /// seconds between discovery and query passes, overrides `scan_interval` from the config
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    scan_interval: Option<Duration>,
+    /// query devices right away on startup instead of after one scan interval
+    #[arg(long)]
+    poll_on_start: bool,
+    /// keeps learned query intervals across restarts
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<String>,
+    /// scan on this adapter only, e.g. hci1
+    #[arg(long, value_name = "NAME", conflicts_with = "adapter_index")]
+    adapter: Option<String>,
+    /// scan on the adapter at this index only
+    #[arg(long, value_name = "INDEX")]
+    adapter_index: Option<usize>,
+    /// address to serve metrics and probes on
+    #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:8080")]
+    listen: SocketAddr,
+    /// off, error, warn, info, debug or trace
+    #[arg(long, env = "AIRTHING_LOG", default_value = "info", value_parser = parse_log_level)]
+    log_level: LevelFilter,
+    /// pretty or json
+    #[arg(long, default_value = "pretty")]
+    log_format: LogFormat,
+    /// also log to this file
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// size the log file is rotated at
+    #[arg(long, value_name = "MB", default_value_t = 10)]
+    log_file_size_mb: u64,
+    /// rotated log files to keep
+    #[arg(long, value_name = "COUNT", default_value_t = 5)]
+    log_file_keep: usize,
+    /// synthesize readings instead of scanning
+    #[arg(long)]
+    mock: bool,
+    /// replay readings from this file, implies --mock
+    #[arg(long, value_name = "PATH")]
+    mock_fixture: Option<String>,
+    /// print the raw sensor payload of every device in range and exit
+    #[arg(long)]
+    dump_raw: bool,
+    /// validate the config, print what it configures and exit
+    #[arg(long)]
+    check_config: bool,
+}
+
+/// exits with usage on invalid arguments, and after printing `--help` or `--version`
+pub fn parse_args() -> Args {
+    Cli::parse().into()
+}
+
+impl From<Cli> for Args {
+    fn from(cli: Cli) -> Self {
+        let adapter = match (cli.adapter, cli.adapter_index) {
+            (Some(name), _) => Some(AdapterSelection::Name(name)),
+            (None, Some(index)) => Some(AdapterSelection::Index(index)),
+            (None, None) => None,
+        };
+        let (max_size, keep) = (cli.log_file_size_mb * 1024 * 1024, cli.log_file_keep);
+        let log_file = cli.log_file.map(|path| LogFile { path, max_size, keep });
+
+        Args {
+            config_path: cli.config_path,
+            discover_all: cli.discover_all,
+            scan_interval: cli.scan_interval,
+            poll_on_start: cli.poll_on_start,
+            state_file: cli.state_file,
+            adapter,
+            listen: cli.listen,
+            log_level: cli.log_level,
+            log_format: cli.log_format,
+            log_file,
+            mock: cli.mock || cli.mock_fixture.is_some(),
+            mock_fixture: cli.mock_fixture,
+            dump_raw: cli.dump_raw,
+            check_config: cli.check_config,
+        }
+    }
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration> {
+    match seconds.parse() {
+        Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+        _ => Err(anyhow!("must be a positive number of seconds, got {}", seconds)),
+    }
+}
+
+fn parse_log_level(level: &str) -> Result<LevelFilter> {
+    level.parse().map_err(|_| anyhow!("unknown log level {}, expected one of off, error, warn, info, debug, trace", level))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{AdapterSelection, Args, Cli};
+
+    fn parse(args: &[&str]) -> Args {
+        Cli::try_parse_from(std::iter::once("airthing").chain(args.iter().copied())).unwrap().into()
+    }
+
+    #[test]
+    fn flags_are_parsed() {
+        let args = parse(&["--config", "/etc/devices.toml", "--adapter-index", "1", "--mock-fixture", "readings.json",
+            "--poll-on-start"]);
+
+        assert_eq!(args.config_path, "/etc/devices.toml");
+        assert!(matches!(args.adapter, Some(AdapterSelection::Index(1))));
+        assert!(args.mock, "a fixture implies mock mode");
+        assert!(args.poll_on_start);
+        assert_eq!(args.listen.port(), 8080);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(Cli::try_parse_from(["airthing", "--scan-interval", "0"]).is_err());
+        assert!(Cli::try_parse_from(["airthing", "--log-level", "loud"]).is_err());
+        assert!(Cli::try_parse_from(["airthing", "--adapter", "hci0", "--adapter-index", "1"]).is_err());
+    }
+}
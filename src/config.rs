@@ -1,49 +1,1253 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
 use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use bcrypt::HashParts;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use thiserror::Error;
 use toml::Value;
 use toml::Value::Table;
 
-pub fn load_device_labels() -> (HashMap<String, Vec<String>>, Vec<String>) {
-    let config_str = fs::read_to_string("devices.toml").unwrap();
-    let value = config_str.parse::<Value>().unwrap();
+use crate::metrics::SENSOR_METRICS;
+use crate::sensor::AIRTHINGS_COMPANY_ID;
+
+/// Wave Plus and Wave refresh their values every 5 minutes
+const DEFAULT_UPDATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_STALE_GRACE: Duration = Duration::from_secs(5 * 60);
+/// well below the connections adapters hold at once, usually 5 to 10
+const DEFAULT_MAX_CONCURRENT_QUERIES: usize = 4;
+/// BlueZ refuses connections beyond about 7
+const DEFAULT_MAX_CONCURRENT_CONNECTIONS: usize = 4;
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+/// 5 minutes at the default scan interval
+const DEFAULT_SCAN_WATCHDOG_PASSES: u32 = 60;
+/// 15 seconds of retries, backing off from one second
+const DEFAULT_SCAN_START_ATTEMPTS: u32 = 5;
+const SCAN_START_BASE_DELAY_MS: u64 = 1000;
+/// last of the label names with `adapter_label`, set to the adapter that read the device
+pub const ADAPTER_LABEL: &str = "adapter";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("config file {path} not found")]
+    NotFound { path: String },
+    #[error("failed to read config file {path}: {source}")]
+    Io { path: String, source: io::Error },
+    #[error("failed to parse config file {path} at line {line}, column {column}: {message}")]
+    Parse { path: String, line: usize, column: usize, message: String },
+    #[error("config file {path} must contain a table at the root")]
+    NotATable { path: String },
+    #[error("config file {path} has serials that are not valid 32-bit unsigned numbers: {}", serials.join(", "))]
+    InvalidSerials { path: String, serials: Vec<String> },
+    #[error("config file {path} has an invalid setting {key}: {message}")]
+    InvalidSetting { path: String, key: String, message: String },
+    #[error("config file {path} defines {key}, already defined in {first_path}")]
+    Duplicate { path: String, key: String, first_path: String },
+    #[error("config file {path} references unset environment variables: {}", names.join(", "))]
+    MissingEnvVars { path: String, names: Vec<String> },
+}
+
+pub struct Config {
+    pub devices: HashMap<u32, DeviceConfig>,
+    /// devices set `enabled = false`, left out of `devices` and never queried, not even by auto discovery
+    pub disabled: HashSet<u32>,
+    pub label_names: Vec<String>,
+    /// value of labels a device does not set, prometheus treats the default empty value as no label at all
+    pub missing_label_value: String,
+    /// scrape devices missing from the config, labelled by serial only
+    pub auto_discover: bool,
+    /// label readings with the adapter they were read through, see `ADAPTER_LABEL`
+    pub adapter_label: bool,
+    /// how often devices refresh their values, unless overridden per device
+    pub update_interval: Duration,
+    /// how long after the last read a device's values are dropped, unless overridden per device,
+    /// `None` for twice its update interval
+    pub stale_after: Option<Duration>,
+    /// how long stale values are kept, marked stale, before they are dropped
+    pub stale_grace: Duration,
+    /// pause between discovery and query passes
+    pub scan_interval: Duration,
+    pub scan_schedule: ScanSchedule,
+    /// passes in a row without any device discovered before the scan is restarted, 0 never restarts it
+    pub scan_watchdog_passes: u32,
+    /// how often starting to scan is attempted on startup before giving up
+    pub scan_start_attempts: u32,
+    /// devices advertising weaker than this, in dBm, are ignored, those without an rssi are not
+    pub min_rssi: Option<i16>,
+    /// devices read at the same time
+    pub max_concurrent_queries: usize,
+    /// devices connected at the same time, from connecting until disconnecting, only read at startup
+    pub max_concurrent_connections: usize,
+    /// how long connecting to, discovering and reading a device may take, each
+    pub query_timeout: Duration,
+    /// manufacturer data the serial is advertised in, tried in order
+    pub company_ids: Vec<u16>,
+    pub query_strategy: QueryStrategy,
+    /// which configured devices must have been read for `/readyz` to pass
+    pub readiness: Readiness,
+    pub retry: RetryConfig,
+    /// drop the connection after every successful read, frees adapter connection slots
+    pub disconnect_after_read: bool,
+    /// keep devices connected and have them push new values, if they can, instead of reading them
+    pub notifications: bool,
+    /// serve the http endpoints over https
+    pub tls: Option<TlsConfig>,
+    pub metrics: MetricsConfig,
+    /// extra units to export values in, on top of the ones the devices report
+    pub units: Units,
+    pub smoothing: Smoothing,
+    pub deadband: Deadband,
+    pub mqtt: Option<MqttConfig>,
+    pub influxdb: Option<InfluxDbConfig>,
+    pub pushgateway: Option<PushgatewayConfig>,
+    pub sqlite: Option<SqliteConfig>,
+    pub csv: Option<CsvConfig>,
+    /// keep the latest readings in this file, for node_exporter's textfile collector
+    pub textfile_path: Option<String>,
+    /// give the samples in the textfile the time they were read at
+    pub textfile_timestamps: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Readiness {
+    Any,
+    All,
+}
+
+/// when discovery and query passes start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanSchedule {
+    /// `scan_interval` after the previous pass ended, the cadence drifts by how long passes take
+    AfterPass,
+    /// every `scan_interval`, a pass running over skips the starts it missed
+    Fixed,
+}
+
+/// when devices are read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryStrategy {
+    /// learns when a device refreshes its values and reads it right after
+    Adaptive,
+    /// reads every device once per interval, no matter when it refreshes
+    Fixed(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceConfig {
+    pub serial: u32,
+    /// ordered as `Config::label_names`
+    pub label_values: Vec<String>,
+    pub update_interval: Duration,
+    pub stale_after: Duration,
+    pub stale_grace: Duration,
+    /// extra units to export values in, the global ones unless set for the device
+    pub units: Units,
+}
+
+impl Config {
+    /// attempts at starting to scan, backing off from a second
+    pub fn scan_start_retry(&self) -> RetryConfig {
+        RetryConfig { attempts: self.scan_start_attempts, base_delay_ms: SCAN_START_BASE_DELAY_MS }
+    }
+
+    /// settings for a device, `None` if it is unknown and auto discovery is off
+    pub fn device(&self, serial: u32) -> Option<DeviceConfig> {
+        if let Some(device) = self.devices.get(&serial) {
+            return Some(device.clone());
+        }
+        if !self.auto_discover || self.disabled.contains(&serial) {
+            return None;
+        }
+
+        let mut label_values = vec![self.missing_label_value.clone(); self.label_names.len()];
+        label_values[0] = serial.to_string();
+        Some(DeviceConfig {
+            serial,
+            label_values,
+            update_interval: self.update_interval,
+            stale_after: self.stale_after.unwrap_or(self.update_interval * 2),
+            stale_grace: self.stale_grace,
+            units: self.units,
+        })
+    }
+}
+
+/// `[retry]` section, how often a failed device query is attempted before giving up
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryConfig {
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    /// delay before the first retry, doubled for every following one
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub base_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: default_retry_attempts(),
+            base_delay_ms: default_retry_base_delay_ms(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// delay after the given failed attempt, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        Duration::from_millis(self.base_delay_ms.saturating_mul(factor))
+    }
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Units {
+    pub radon: RadonUnit,
+    pub temperature: TemperatureUnit,
+    pub humidity: HumidityUnit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum RadonUnit {
+    /// as reported by the devices
+    #[default]
+    #[serde(rename = "Bq/m3")]
+    BecquerelPerCubicMeter,
+    /// also exported as `radon_short_pcil` and `radon_long_pcil`
+    #[serde(rename = "pCi/L")]
+    PicocuriePerLiter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum TemperatureUnit {
+    /// as reported by the devices
+    #[default]
+    #[serde(rename = "C")]
+    Celsius,
+    /// also exported as `temperature_fahrenheit`
+    #[serde(rename = "F")]
+    Fahrenheit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum HumidityUnit {
+    /// as reported by the devices
+    #[default]
+    #[serde(rename = "%")]
+    Percent,
+    /// also exported as `humidity_ratio`, 0 to 1
+    #[serde(rename = "ratio")]
+    Ratio,
+}
+
+/// `[smoothing]` section, weight of a new reading in the exported exponential moving average, per value.
+/// 1 exports readings as they are, lower values smooth more.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Smoothing {
+    pub humidity: Option<f64>,
+    pub temperature: Option<f64>,
+    pub atm_pressure: Option<f64>,
+    pub radon_short: Option<f64>,
+    pub radon_long: Option<f64>,
+    pub co2: Option<f64>,
+    pub voc: Option<f64>,
+}
+
+impl Smoothing {
+    fn alphas(&self) -> [(&'static str, Option<f64>); 7] {
+        [
+            ("humidity", self.humidity),
+            ("temperature", self.temperature),
+            ("atm_pressure", self.atm_pressure),
+            ("radon_short", self.radon_short),
+            ("radon_long", self.radon_long),
+            ("co2", self.co2),
+            ("voc", self.voc),
+        ]
+    }
+}
+
+/// `[deadband]` section, how far a value has to move from the previous reading for it to count as refreshed,
+/// per value, in the value's unit. Values without one count as refreshed on any difference.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Deadband {
+    pub humidity: Option<f64>,
+    pub temperature: Option<f64>,
+    pub atm_pressure: Option<f64>,
+    pub radon_short: Option<f64>,
+    pub radon_long: Option<f64>,
+    pub co2: Option<f64>,
+    pub voc: Option<f64>,
+}
+
+impl Deadband {
+    fn thresholds(&self) -> [(&'static str, Option<f64>); 7] {
+        [
+            ("humidity", self.humidity),
+            ("temperature", self.temperature),
+            ("atm_pressure", self.atm_pressure),
+            ("radon_short", self.radon_short),
+            ("radon_long", self.radon_long),
+            ("co2", self.co2),
+            ("voc", self.voc),
+        ]
+    }
+}
 
-    let mut devices: HashMap<String, HashMap<String, String>> = HashMap::new();
+/// `[metrics]` section
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    /// prefix of all metric names
+    #[serde(default = "default_metrics_namespace")]
+    pub namespace: String,
+    /// export sensor metrics unprefixed, as before they were namespaced, for existing dashboards
+    #[serde(default)]
+    pub legacy_names: bool,
+    /// require http basic auth for `/metrics` and `/devices`
+    pub auth: Option<AuthConfig>,
+    /// sensor gauges to export, out of `metrics::SENSOR_METRICS`, all of them if unset
+    pub enabled: Option<Vec<String>>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            namespace: default_metrics_namespace(),
+            legacy_names: false,
+            auth: None,
+            enabled: None,
+        }
+    }
+}
+
+fn default_metrics_namespace() -> String {
+    String::from("airthing")
+}
+
+/// `[metrics.auth]` section
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    pub username: String,
+    /// bcrypt hash, e.g. from `htpasswd -nbB <username> <password>`
+    pub password_hash: String,
+}
+
+/// `[tls]` section, pem encoded certificate chain and private key
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// `[mqtt]` section, publishes every reading as json to `<topic_prefix>/<serial>/state`
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    /// publish home assistant discovery configs for every device
+    #[serde(default = "default_true")]
+    pub discovery: bool,
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+/// `[influxdb]` section, writes every reading to an InfluxDB v2 bucket, tagged with the device labels
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "influxdb"), allow(dead_code))]
+pub struct InfluxDbConfig {
+    /// e.g. `http://influxdb.local:8086`, only plain http is supported
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    #[serde(default = "default_influxdb_measurement")]
+    pub measurement: String,
+}
+
+/// `[sqlite]` section, appends every reading to a SQLite database
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct SqliteConfig {
+    /// created if missing
+    pub path: String,
+}
+
+/// `[csv]` section, appends every reading to a CSV file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CsvConfig {
+    /// created if missing
+    pub path: String,
+}
+
+/// `[pushgateway]` section, pushes all metrics periodically, grouped by serial, for when prometheus can't scrape
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "pushgateway"), allow(dead_code))]
+pub struct PushgatewayConfig {
+    /// e.g. `http://pushgateway.local:9091`
+    pub url: String,
+    #[serde(default = "default_pushgateway_job")]
+    pub job: String,
+    /// seconds between pushes
+    #[serde(default = "default_pushgateway_interval")]
+    pub interval: u64,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+fn default_pushgateway_job() -> String {
+    String::from("airthing")
+}
+
+fn default_pushgateway_interval() -> u64 {
+    60
+}
+
+fn default_influxdb_measurement() -> String {
+    String::from("airthing")
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    String::from("airthingrs")
+}
+
+fn default_mqtt_client_id() -> String {
+    String::from("airthingrs")
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    String::from("homeassistant")
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// `path` is a config file, or a directory whose `*.toml` files are merged, each setting and device defined once
+pub fn load_config(path: &str) -> Result<Config, ConfigError> {
+    if Path::new(path).is_dir() {
+        config_from_toml(path, read_toml_dir(path)?)
+    } else {
+        parse_config(path, &read_file(path)?)
+    }
+}
+
+/// the config in `toml`, `path` only names it in errors
+pub fn parse_config(path: &str, toml: &str) -> Result<Config, ConfigError> {
+    config_from_toml(path, parse_toml(path, toml)?)
+}
+
+fn config_from_toml(path: &str, mut value: Value) -> Result<Config, ConfigError> {
+    let mut missing_vars = BTreeSet::new();
+    expand_env_vars(&mut value, &|name| env::var(name).ok(), &mut missing_vars);
+    if !missing_vars.is_empty() {
+        return Err(ConfigError::MissingEnvVars { path: path.to_string(), names: missing_vars.into_iter().collect() });
+    }
+
+    let mut devices: HashMap<u32, HashMap<String, String>> = HashMap::new();
+    let mut update_intervals: HashMap<u32, Duration> = HashMap::new();
+    let mut stale_afters: HashMap<u32, Duration> = HashMap::new();
+    let mut radon_units: HashMap<u32, RadonUnit> = HashMap::new();
+    let mut temperature_units: HashMap<u32, TemperatureUnit> = HashMap::new();
+    let mut humidity_units: HashMap<u32, HumidityUnit> = HashMap::new();
+    let mut disabled: HashSet<u32> = HashSet::new();
     let mut label_names: HashSet<String> = HashSet::new();
+    let mut invalid_serials: Vec<String> = Vec::new();
+    let mut auto_discover = false;
+    let mut adapter_label = false;
+    let mut missing_label_value = String::new();
+    let mut update_interval = DEFAULT_UPDATE_INTERVAL;
+    let mut stale_after = None;
+    let mut stale_grace = DEFAULT_STALE_GRACE;
+    let mut scan_interval = DEFAULT_SCAN_INTERVAL;
+    let mut scan_schedule = ScanSchedule::AfterPass;
+    let mut scan_watchdog_passes = DEFAULT_SCAN_WATCHDOG_PASSES;
+    let mut scan_start_attempts = DEFAULT_SCAN_START_ATTEMPTS;
+    let mut min_rssi = None;
+    let mut max_concurrent_queries = DEFAULT_MAX_CONCURRENT_QUERIES;
+    let mut max_concurrent_connections = DEFAULT_MAX_CONCURRENT_CONNECTIONS;
+    let mut query_timeout = DEFAULT_QUERY_TIMEOUT;
+    let mut company_ids = vec![AIRTHINGS_COMPANY_ID];
+    let mut query_strategy = String::from("adaptive");
+    let mut query_interval = None;
+    let mut readiness = Readiness::Any;
+    let mut retry = RetryConfig::default();
+    let mut disconnect_after_read = false;
+    let mut notifications = false;
+    let mut tls = None;
+    let mut metrics = MetricsConfig::default();
+    let mut units = Units::default();
+    let mut smoothing = Smoothing::default();
+    let mut deadband = Deadband::default();
+    let mut mqtt = None;
+    let mut influxdb = None;
+    let mut pushgateway = None;
+    let mut sqlite = None;
+    let mut csv = None;
+    let mut textfile_path = None;
+    let mut textfile_timestamps = true;
     if let Table(root_table) = value {
-        for (serial, labels_value) in root_table {
+        for (serial_str, labels_value) in root_table {
+            // known keys at the root are global settings and sections, other tables are devices
+            match serial_str.as_str() {
+                "auto_discover" => {
+                    auto_discover = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "adapter_label" => {
+                    adapter_label = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "missing_label_value" => {
+                    missing_label_value = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "update_interval" => {
+                    update_interval = parse_seconds(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "stale_after" => {
+                    stale_after = Some(parse_seconds(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "stale_grace" => {
+                    // 0 drops stale values right away
+                    stale_grace = Duration::from_secs(parse_setting(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "scan_interval" => {
+                    scan_interval = parse_seconds(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "scan_schedule" => {
+                    scan_schedule = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "scan_watchdog_passes" => {
+                    scan_watchdog_passes = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "scan_start_attempts" => {
+                    scan_start_attempts = parse_setting(path, &serial_str, labels_value)?;
+                    if scan_start_attempts == 0 {
+                        return Err(ConfigError::InvalidSetting {
+                            path: path.to_string(),
+                            key: serial_str,
+                            message: String::from("must be at least one"),
+                        });
+                    }
+                    continue;
+                }
+                "min_rssi" => {
+                    min_rssi = Some(parse_setting(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "max_concurrent_queries" => {
+                    max_concurrent_queries = parse_setting(path, &serial_str, labels_value)?;
+                    if max_concurrent_queries == 0 {
+                        return Err(ConfigError::InvalidSetting {
+                            path: path.to_string(),
+                            key: serial_str,
+                            message: String::from("must be at least one"),
+                        });
+                    }
+                    continue;
+                }
+                "max_concurrent_connections" => {
+                    max_concurrent_connections = parse_setting(path, &serial_str, labels_value)?;
+                    if max_concurrent_connections == 0 {
+                        return Err(ConfigError::InvalidSetting {
+                            path: path.to_string(),
+                            key: serial_str,
+                            message: String::from("must be at least one"),
+                        });
+                    }
+                    continue;
+                }
+                "query_timeout" => {
+                    query_timeout = parse_seconds(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "company_ids" => {
+                    company_ids = parse_setting(path, &serial_str, labels_value)?;
+                    if company_ids.is_empty() {
+                        return Err(ConfigError::InvalidSetting {
+                            path: path.to_string(),
+                            key: serial_str,
+                            message: String::from("must list at least one"),
+                        });
+                    }
+                    continue;
+                }
+                "query_strategy" => {
+                    query_strategy = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "query_interval" => {
+                    query_interval = Some(parse_seconds(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "readiness" => {
+                    readiness = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "retry" => {
+                    retry = parse_setting(path, &serial_str, labels_value)?;
+                    if retry.attempts == 0 {
+                        return Err(ConfigError::InvalidSetting {
+                            path: path.to_string(),
+                            key: String::from("retry.attempts"),
+                            message: String::from("must be at least one"),
+                        });
+                    }
+                    continue;
+                }
+                "disconnect_after_read" => {
+                    disconnect_after_read = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "notifications" => {
+                    notifications = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "tls" => {
+                    tls = Some(parse_setting(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "radon_unit" => {
+                    units.radon = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "temperature_unit" => {
+                    units.temperature = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "humidity_unit" => {
+                    units.humidity = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                "metrics" => {
+                    metrics = parse_setting(path, &serial_str, labels_value)?;
+                    let unknown: Vec<&String> = metrics.enabled.iter().flatten()
+                        .filter(|name| !SENSOR_METRICS.contains(&name.as_str()))
+                        .collect();
+                    if !unknown.is_empty() {
+                        return Err(ConfigError::InvalidSetting {
+                            path: path.to_string(),
+                            key: String::from("metrics.enabled"),
+                            message: format!("unknown metrics {:?}, expected some of {}", unknown, SENSOR_METRICS.join(", ")),
+                        });
+                    }
+                    if let Some(auth) = &metrics.auth {
+                        if let Err(err) = auth.password_hash.parse::<HashParts>() {
+                            return Err(ConfigError::InvalidSetting {
+                                path: path.to_string(),
+                                key: String::from("metrics.auth.password_hash"),
+                                message: err.to_string(),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                "smoothing" => {
+                    smoothing = parse_setting(path, &serial_str, labels_value)?;
+                    for (name, alpha) in smoothing.alphas().iter() {
+                        if alpha.is_some_and(|alpha| !(alpha > 0.0 && alpha <= 1.0)) {
+                            return Err(ConfigError::InvalidSetting {
+                                path: path.to_string(),
+                                key: format!("smoothing.{}", name),
+                                message: String::from("must be above 0 and at most 1"),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                "deadband" => {
+                    deadband = parse_setting(path, &serial_str, labels_value)?;
+                    for (name, threshold) in deadband.thresholds().iter() {
+                        if threshold.is_some_and(|threshold| !(threshold >= 0.0 && threshold.is_finite())) {
+                            return Err(ConfigError::InvalidSetting {
+                                path: path.to_string(),
+                                key: format!("deadband.{}", name),
+                                message: String::from("must be 0 or more"),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                "mqtt" => {
+                    mqtt = Some(parse_setting(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "influxdb" => {
+                    influxdb = Some(parse_setting(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "pushgateway" => {
+                    let config: PushgatewayConfig = parse_setting(path, &serial_str, labels_value)?;
+                    if config.interval == 0 {
+                        return Err(ConfigError::InvalidSetting {
+                            path: path.to_string(),
+                            key: String::from("pushgateway.interval"),
+                            message: String::from("must be at least one second"),
+                        });
+                    }
+                    pushgateway = Some(config);
+                    continue;
+                }
+                "sqlite" => {
+                    sqlite = Some(parse_setting(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "csv" => {
+                    csv = Some(parse_setting(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "textfile_path" => {
+                    textfile_path = Some(parse_setting(path, &serial_str, labels_value)?);
+                    continue;
+                }
+                "textfile_timestamps" => {
+                    textfile_timestamps = parse_setting(path, &serial_str, labels_value)?;
+                    continue;
+                }
+                _ if !labels_value.is_table() => return Err(ConfigError::InvalidSetting {
+                    path: path.to_string(),
+                    key: serial_str,
+                    message: String::from("unknown setting"),
+                }),
+                _ => {}
+            }
+
+            let serial = match serial_str.parse::<u32>() {
+                Ok(serial) => serial,
+                Err(_) => {
+                    invalid_serials.push(serial_str);
+                    continue;
+                }
+            };
+
             let mut labels_map: HashMap<String, String> = HashMap::new();
-            labels_map.insert(String::from("serial"), serial.clone());
+            labels_map.insert(String::from("serial"), serial.to_string());
 
             if let Table(device_table) = labels_value {
+                // known keys are per device settings, other string values are labels
                 for (name, value) in device_table {
-                    if let Value::String(str_value) = value {
-                        labels_map.insert(name.clone(), str_value);
-                        label_names.insert(name);
+                    match (name.as_str(), value) {
+                        ("update_interval", value) => {
+                            let key = format!("{}.{}", serial_str, name);
+                            update_intervals.insert(serial, parse_seconds(path, &key, value)?);
+                        }
+                        ("stale_after", value) => {
+                            let key = format!("{}.{}", serial_str, name);
+                            stale_afters.insert(serial, parse_seconds(path, &key, value)?);
+                        }
+                        ("radon_unit", value) => {
+                            let key = format!("{}.{}", serial_str, name);
+                            radon_units.insert(serial, parse_setting(path, &key, value)?);
+                        }
+                        ("temperature_unit", value) => {
+                            let key = format!("{}.{}", serial_str, name);
+                            temperature_units.insert(serial, parse_setting(path, &key, value)?);
+                        }
+                        ("humidity_unit", value) => {
+                            let key = format!("{}.{}", serial_str, name);
+                            humidity_units.insert(serial, parse_setting(path, &key, value)?);
+                        }
+                        ("enabled", value) => {
+                            let key = format!("{}.{}", serial_str, name);
+                            if !parse_setting::<bool>(path, &key, value)? {
+                                disabled.insert(serial);
+                            }
+                        }
+                        (_, Value::String(str_value)) => {
+                            labels_map.insert(name.clone(), str_value);
+                            label_names.insert(name);
+                        }
+                        (_, _) => return Err(ConfigError::InvalidSetting {
+                            path: path.to_string(),
+                            key: format!("{}.{}", serial_str, name),
+                            message: String::from("unknown setting"),
+                        }),
                     }
                 }
             }
 
             devices.insert(serial, labels_map);
         }
+    } else {
+        return Err(ConfigError::NotATable { path: path.to_string() });
+    }
+
+    if !invalid_serials.is_empty() {
+        invalid_serials.sort();
+        return Err(ConfigError::InvalidSerials { path: path.to_string(), serials: invalid_serials });
     }
 
+    let query_strategy = match (query_strategy.as_str(), query_interval) {
+        ("adaptive", None) => QueryStrategy::Adaptive,
+        ("fixed", query_interval) => QueryStrategy::Fixed(query_interval.unwrap_or(update_interval)),
+        ("adaptive", Some(_)) => return Err(ConfigError::InvalidSetting {
+            path: path.to_string(),
+            key: String::from("query_interval"),
+            message: String::from("only applies to query_strategy = \"fixed\""),
+        }),
+        (_, _) => return Err(ConfigError::InvalidSetting {
+            path: path.to_string(),
+            key: String::from("query_strategy"),
+            message: format!("expected \"adaptive\" or \"fixed\", got {:?}", query_strategy),
+        }),
+    };
+
+    if notifications && disconnect_after_read {
+        return Err(ConfigError::InvalidSetting {
+            path: path.to_string(),
+            key: String::from("notifications"),
+            message: String::from("devices need to stay connected to push values, turn disconnect_after_read off"),
+        });
+    }
+
+    // sorted, so a reloaded config lines up with the registered metrics
+    let mut label_names: Vec<String> = label_names.into_iter().collect();
+    label_names.sort();
     let mut label_names_vec = vec![String::from("serial")];
-    for name in label_names {
-        label_names_vec.push(name);
+    label_names_vec.extend(label_names);
+
+    for (serial, missing) in missing_labels(&devices, &label_names_vec) {
+        warn!("device {} in {} does not set labels {}, exported as {:?}", serial, path, missing.join(", "), missing_label_value);
     }
 
-    let default_value = String::from("");
-    let mut devices_labels: HashMap<String, Vec<String>> = HashMap::new();
+    // set per reading, devices start out with the placeholder
+    if adapter_label {
+        if label_names_vec.iter().any(|name| name == ADAPTER_LABEL) {
+            return Err(ConfigError::InvalidSetting {
+                path: path.to_string(),
+                key: String::from("adapter_label"),
+                message: format!("a device already sets the {} label", ADAPTER_LABEL),
+            });
+        }
+        label_names_vec.push(String::from(ADAPTER_LABEL));
+    }
+
+    // the labels of disabled devices still count towards the label names, so disabling one keeps them as they are
+    let mut devices_config: HashMap<u32, DeviceConfig> = HashMap::new();
     for (serial, device_labels) in devices {
+        if disabled.contains(&serial) {
+            continue;
+        }
         let mut label_values: Vec<String> = Vec::new();
 
         for name in &label_names_vec {
-            let value = device_labels.get(name).unwrap_or(&default_value);
+            let value = device_labels.get(name).unwrap_or(&missing_label_value);
             label_values.push(value.clone());
         }
 
-        devices_labels.insert(serial, label_values);
+        let device_update_interval = update_intervals.get(&serial).copied().unwrap_or(update_interval);
+        devices_config.insert(serial, DeviceConfig {
+            serial,
+            label_values,
+            update_interval: device_update_interval,
+            stale_after: stale_afters.get(&serial).copied().or(stale_after).unwrap_or(device_update_interval * 2),
+            stale_grace,
+            units: Units {
+                radon: radon_units.get(&serial).copied().unwrap_or(units.radon),
+                temperature: temperature_units.get(&serial).copied().unwrap_or(units.temperature),
+                humidity: humidity_units.get(&serial).copied().unwrap_or(units.humidity),
+            },
+        });
+    }
+
+    Ok(Config {
+        devices: devices_config,
+        disabled,
+        label_names: label_names_vec,
+        missing_label_value,
+        auto_discover,
+        adapter_label,
+        update_interval,
+        stale_after,
+        stale_grace,
+        scan_interval,
+        scan_schedule,
+        scan_watchdog_passes,
+        scan_start_attempts,
+        min_rssi,
+        max_concurrent_queries,
+        max_concurrent_connections,
+        query_timeout,
+        company_ids,
+        query_strategy,
+        readiness,
+        retry,
+        disconnect_after_read,
+        notifications,
+        tls,
+        metrics,
+        units,
+        smoothing,
+        deadband,
+        mqtt,
+        influxdb,
+        pushgateway,
+        sqlite,
+        csv,
+        textfile_path,
+        textfile_timestamps,
+    })
+}
+
+fn read_toml(path: &str) -> Result<Value, ConfigError> {
+    parse_toml(path, &read_file(path)?)
+}
+
+fn read_file(path: &str) -> Result<String, ConfigError> {
+    fs::read_to_string(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => ConfigError::NotFound { path: path.to_string() },
+        _ => ConfigError::Io { path: path.to_string(), source: err },
+    })
+}
+
+fn parse_toml(path: &str, toml: &str) -> Result<Value, ConfigError> {
+    toml.parse::<Value>().map_err(|err| {
+        // toml reports zero-based positions, editors count from one
+        let (line, column) = err.line_col().map_or((0, 0), |(line, col)| (line + 1, col + 1));
+        ConfigError::Parse { path: path.to_string(), line, column, message: err.to_string() }
+    })
+}
+
+/// merges the root tables of the files in name order
+fn read_toml_dir(dir: &str) -> Result<Value, ConfigError> {
+    let io_error = |err| ConfigError::Io { path: dir.to_string(), source: err };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.is_file() && path.extension().is_some_and(|extension| extension == "toml") {
+            paths.push(path.to_string_lossy().into_owned());
+        }
+    }
+    paths.sort();
+
+    let mut merged = toml::map::Map::new();
+    let mut defined_in: HashMap<String, String> = HashMap::new();
+    for path in paths {
+        let table = match read_toml(&path)? {
+            Table(table) => table,
+            _ => return Err(ConfigError::NotATable { path }),
+        };
+        for (key, value) in table {
+            if let Some(first_path) = defined_in.get(&key) {
+                return Err(ConfigError::Duplicate { path, key, first_path: first_path.clone() });
+            }
+            defined_in.insert(key.clone(), path.clone());
+            merged.insert(key, value);
+        }
     }
+    Ok(Table(merged))
+}
+
+/// replaces `${NAME}` in all string values with the variable's value, noting the names of unset ones in `missing`
+fn expand_env_vars(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>, missing: &mut BTreeSet<String>) {
+    match value {
+        Value::String(string) => *string = expand_str(string, lookup, missing),
+        Value::Array(values) => values.iter_mut().for_each(|value| expand_env_vars(value, lookup, missing)),
+        Table(table) => table.iter_mut().for_each(|(_, value)| expand_env_vars(value, lookup, missing)),
+        _ => {}
+    }
+}
 
-    return (devices_labels, label_names_vec);
+fn expand_str(string: &str, lookup: &dyn Fn(&str) -> Option<String>, missing: &mut BTreeSet<String>) -> String {
+    let mut expanded = String::with_capacity(string.len());
+    let mut rest = string;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            // unterminated, kept as it is
+            None => break,
+        };
+        expanded.push_str(&rest[..start]);
+        let name = &rest[start + 2..end];
+        match lookup(name) {
+            Some(value) => expanded.push_str(&value),
+            None => {
+                missing.insert(name.to_string());
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// labels other devices set but these do not, by serial
+fn missing_labels(devices: &HashMap<u32, HashMap<String, String>>, label_names: &[String]) -> BTreeMap<u32, Vec<String>> {
+    devices.iter()
+        .filter_map(|(serial, labels)| {
+            let mut missing: Vec<String> = label_names.iter()
+                .filter(|name| !labels.contains_key(*name))
+                .cloned()
+                .collect();
+            missing.sort();
+            Some((*serial, missing)).filter(|(_, missing)| !missing.is_empty())
+        })
+        .collect()
+}
+
+fn parse_seconds(path: &str, key: &str, value: Value) -> Result<Duration, ConfigError> {
+    let seconds: u64 = parse_setting(path, key, value)?;
+    if seconds == 0 {
+        return Err(ConfigError::InvalidSetting {
+            path: path.to_string(),
+            key: key.to_string(),
+            message: String::from("must be at least one second"),
+        });
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+fn parse_setting<T: DeserializeOwned>(path: &str, key: &str, value: Value) -> Result<T, ConfigError> {
+    value.try_into().map_err(|err| ConfigError::InvalidSetting {
+        path: path.to_string(),
+        key: key.to_string(),
+        message: err.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+    use std::fs;
+    use std::time::Duration;
+
+    use toml::Value;
+
+    use super::{
+        expand_env_vars, load_config, missing_labels, parse_config, Config, ConfigError, QueryStrategy, RadonUnit,
+        ScanSchedule, TemperatureUnit, Units,
+    };
+
+    fn load(name: &str, toml: &str) -> Config {
+        parse_config(&format!("{}.toml", name), toml).unwrap()
+    }
+
+    #[test]
+    fn label_names_are_the_union_of_all_devices() {
+        let config = load("union", r#"
+            [1]
+            room = "Study"
+            floor = "1"
+
+            [2]
+            room = "Bedroom"
+            building = "B"
+        "#);
+
+        assert_eq!(config.label_names, vec!["serial", "building", "floor", "room"], "serial first, the rest sorted");
+        assert_eq!(config.devices[&1].label_values, vec!["1", "", "1", "Study"]);
+        assert_eq!(config.devices[&2].label_values, vec!["2", "B", "", "Bedroom"], "missing labels are empty");
+    }
+
+    #[test]
+    fn missing_labels_get_the_placeholder() {
+        let config = load("placeholder", r#"
+            auto_discover = true
+            missing_label_value = "unknown"
+
+            [1]
+            room = "Study"
+            floor = "1"
+
+            [2]
+            room = "Bedroom"
+        "#);
+
+        let floor = config.label_names.iter().position(|name| name == "floor").unwrap();
+        assert_eq!(config.devices[&1].label_values[floor], "1");
+        assert_eq!(config.devices[&2].label_values[floor], "unknown");
+        assert_eq!(config.device(3).unwrap().label_values[floor], "unknown");
+        assert_eq!(config.device(3).unwrap().label_values[0], "3");
+    }
+
+    #[test]
+    fn disabled_devices_are_not_queried_but_keep_their_labels() {
+        let config = load("disabled", r#"
+            auto_discover = true
+
+            [1]
+            room = "Study"
+
+            [2]
+            floor = "1"
+            enabled = false
+        "#);
+
+        assert_eq!(config.label_names, vec!["serial", "floor", "room"]);
+        assert!(config.devices.contains_key(&1));
+        assert!(config.device(2).is_none(), "not picked up by auto discovery either");
+    }
+
+    #[test]
+    fn units_can_be_set_per_device() {
+        let config = load("units", r#"
+            temperature_unit = "F"
+
+            [1]
+            room = "Study"
+            temperature_unit = "C"
+
+            [2]
+            room = "Guest house"
+            radon_unit = "pCi/L"
+        "#);
+
+        assert_eq!(config.devices[&1].units, Units::default());
+        let guest_house = Units { radon: RadonUnit::PicocuriePerLiter, temperature: TemperatureUnit::Fahrenheit,
+            ..Units::default() };
+        assert_eq!(config.devices[&2].units, guest_house);
+        assert_eq!(config.label_names, vec!["serial", "room"], "units are not labels");
+    }
+
+    #[test]
+    fn adapter_label_comes_last() {
+        let config = load("adapter", r#"
+            adapter_label = true
+            missing_label_value = "unknown"
+
+            [1]
+            room = "Study"
+        "#);
+
+        assert_eq!(config.label_names, vec!["serial", "room", "adapter"]);
+        assert_eq!(config.devices[&1].label_values, vec!["1", "Study", "unknown"]);
+    }
+
+    #[test]
+    fn staleness_follows_the_update_interval_unless_set() {
+        let config = load("stale", r#"
+            auto_discover = true
+            update_interval = 300
+
+            [1]
+            update_interval = 150
+
+            [2]
+            stale_after = 900
+        "#);
+
+        assert_eq!(config.devices[&1].stale_after, Duration::from_secs(300));
+        assert_eq!(config.devices[&2].stale_after, Duration::from_secs(900));
+        assert_eq!(config.device(3).unwrap().stale_after, Duration::from_secs(600));
+    }
+
+    #[test]
+    fn passes_start_after_the_previous_one_by_default() {
+        assert_eq!(load("default", "").scan_schedule, ScanSchedule::AfterPass);
+        assert_eq!(load("fixed", "scan_schedule = \"fixed\"").scan_schedule, ScanSchedule::Fixed);
+        assert!(parse_config("burst", "scan_schedule = \"burst\"").is_err());
+    }
+
+    #[test]
+    fn fixed_query_interval_defaults_to_the_update_interval() {
+        assert_eq!(load("adaptive", "").query_strategy, QueryStrategy::Adaptive);
+
+        let config = load("fixed", r#"
+            update_interval = 150
+            query_strategy = "fixed"
+        "#);
+        assert_eq!(config.query_strategy, QueryStrategy::Fixed(Duration::from_secs(150)));
+
+        let config = load("fixed-interval", r#"
+            query_strategy = "fixed"
+            query_interval = 60
+        "#);
+        assert_eq!(config.query_strategy, QueryStrategy::Fixed(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn config_dir_files_are_merged() {
+        let dir = std::env::temp_dir().join(format!("airthing-conf.d-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("00-global.toml"), "update_interval = 150").unwrap();
+        fs::write(dir.join("study.toml"), "[1]\nroom = \"Study\"").unwrap();
+        fs::write(dir.join("bedroom.toml"), "[2]\nroom = \"Bedroom\"").unwrap();
+        fs::write(dir.join("notes.txt"), "not a config").unwrap();
+
+        let config = load_config(dir.to_str().unwrap()).unwrap();
+        assert_eq!(config.devices.len(), 2);
+        assert_eq!(config.update_interval, Duration::from_secs(150));
+
+        fs::write(dir.join("study-copy.toml"), "[1]\nroom = \"Office\"").unwrap();
+        let err = load_config(dir.to_str().unwrap()).err().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(err, ConfigError::Duplicate { key, .. } if key == "1"));
+    }
+
+    #[test]
+    fn env_vars_are_expanded_in_strings() {
+        let lookup = |name: &str| match name {
+            "INFLUX_TOKEN" => Some(String::from("secret")),
+            "ROOM" => Some(String::from("Study")),
+            _ => None,
+        };
+        let mut value: Value = r#"
+            [influxdb]
+            token = "${INFLUX_TOKEN}"
+            url = "http://${INFLUX_HOST}:${INFLUX_PORT}"
+
+            [1]
+            room = "${ROOM} ${UNTERMINATED"
+        "#.parse().unwrap();
+        let mut missing = BTreeSet::new();
+
+        expand_env_vars(&mut value, &lookup, &mut missing);
+
+        assert_eq!(value["influxdb"]["token"].as_str(), Some("secret"));
+        assert_eq!(value["1"]["room"].as_str(), Some("Study ${UNTERMINATED"));
+        assert_eq!(missing.into_iter().collect::<Vec<_>>(), vec!["INFLUX_HOST", "INFLUX_PORT"]);
+    }
+
+    #[test]
+    fn missing_labels_are_listed_per_device() {
+        let labels = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+        };
+        let mut devices = HashMap::new();
+        devices.insert(1, labels(&[("serial", "1"), ("room", "Study"), ("floor", "1")]));
+        devices.insert(2, labels(&[("serial", "2")]));
+        let names = vec![String::from("serial"), String::from("room"), String::from("floor")];
+
+        let missing = missing_labels(&devices, &names);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[&2], vec!["floor", "room"]);
+    }
 }
@@ -0,0 +1,79 @@
+//! Bounds how many devices are connected at once, adapters refuse connections beyond their own limit.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::source::SensorSource;
+
+/// A connection slot, held from connecting to a device until it is disconnected.
+pub struct ConnectionSlot {
+    serial: u32,
+    _permit: OwnedSemaphorePermit,
+}
+
+pub struct ConnectionLimit {
+    permits: Arc<Semaphore>,
+    /// devices left connected between queries, longest idle first, with the slots they hold
+    idle: RefCell<VecDeque<ConnectionSlot>>,
+    /// a slot was left idle, queries waiting for one may take it over
+    idled: Notify,
+}
+
+impl ConnectionLimit {
+    pub fn new(max_connections: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            permits: Arc::new(Semaphore::new(max_connections)),
+            idle: RefCell::new(VecDeque::new()),
+            idled: Notify::new(),
+        }
+    }
+
+    /// The slot `serial` was left connected with, a free one, or the one of the device left connected the longest,
+    /// disconnected for it. Waits for a query to finish if all slots are in use.
+    pub async fn acquire(&self, source: &dyn SensorSource, serial: u32) -> ConnectionSlot {
+        loop {
+            if let Some(slot) = self.take_idle(Some(serial)) {
+                return slot;
+            }
+            if let Ok(permit) = Arc::clone(&self.permits).try_acquire_owned() {
+                return ConnectionSlot { serial, _permit: permit };
+            }
+            if let Some(slot) = self.take_idle(None) {
+                debug!("all connections in use, disconnecting peripheral {} for {}", slot.serial, serial);
+                if let Err(err) = source.disconnect(slot.serial).await {
+                    debug!("Failed to disconnect from peripheral {}: {:?}", slot.serial, err);
+                }
+                return ConnectionSlot { serial, _permit: slot._permit };
+            }
+            tokio::select! {
+                permit = Arc::clone(&self.permits).acquire_owned() => {
+                    // never closed
+                    if let Ok(permit) = permit {
+                        return ConnectionSlot { serial, _permit: permit };
+                    }
+                }
+                _ = self.idled.notified() => {}
+            }
+        }
+    }
+
+    /// the device stays connected, its slot taken until another device needs it
+    pub fn keep(&self, slot: ConnectionSlot) {
+        self.idle.borrow_mut().push_back(slot);
+        self.idled.notify_waiters();
+    }
+
+    /// the slot of `serial`, or with `None` the longest idle one
+    fn take_idle(&self, serial: Option<u32>) -> Option<ConnectionSlot> {
+        let mut idle = self.idle.borrow_mut();
+        let index = match serial {
+            Some(serial) => idle.iter().position(|slot| slot.serial == serial)?,
+            None => 0,
+        };
+        idle.remove(index)
+    }
+}
@@ -1,38 +1,80 @@
-use std::rc::Rc;
-use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use log::{info, warn};
+use prometheus::core::{Atomic, GenericGaugeVec};
+use prometheus::{GaugeVec, IntGaugeVec};
+use crate::config::{
+    Deadband, DeviceConfig, HumidityUnit, QueryStrategy, RadonUnit, Smoothing, TemperatureUnit, Units, ADAPTER_LABEL,
+};
 use crate::sensor::SensorValues;
 use crate::metrics::CustomMetrics;
+use crate::readings::DeviceReading;
+use crate::sink::ReadingSink;
 
-pub trait PeripheralControl<T: Eq> {
+pub type Sinks = Arc<Vec<Box<dyn ReadingSink>>>;
+
+pub trait PeripheralControl<T: Eq>: Send {
     fn should_query(&self, now: Instant) -> bool;
     fn update(&mut self, now: Instant, value: &T);
-    fn remove_metric_if_stale(&self, now: Instant);
+    /// labels the following updates with the adapter they are read through, with `adapter_label` on,
+    /// the series labelled with a previous adapter are dropped
+    fn set_adapter(&mut self, adapter: &str);
+    /// marks the device's values stale once they are, drops them after the grace period
+    fn remove_metric_if_stale(&mut self, now: Instant);
+    /// drops all of the device's metrics, e.g. when it is no longer configured
+    fn remove_metrics(&self);
+    fn expected_interval(&self) -> Option<(Instant, Instant)>;
+    fn restore_expected_interval(&mut self, now: Instant, expected_interval: (Instant, Instant));
 }
 
 pub fn new_peripheral_control(
-    update_interval: Duration,
-    metrics: Rc<CustomMetrics>,
-    label_values: &Vec<String>,
+    device: &DeviceConfig,
+    label_names: &[String],
+    query_strategy: QueryStrategy,
+    smoothing: Smoothing,
+    deadband: Deadband,
+    metrics: Arc<CustomMetrics>,
+    sinks: Sinks,
 ) -> Box<dyn PeripheralControl<SensorValues>> {
-    return Box::new(
+    Box::new(
         PeripheralQueryControl{
-            metrics, update_interval,
-            label_values: label_values.clone(),
-            query_control: new_query_control(update_interval),
+            serial: device.serial,
+            units: device.units,
+            smoothing, deadband, metrics, sinks,
+            stale_after: device.stale_after,
+            stale_grace: device.stale_grace,
+            staleness: Staleness::Fresh,
+            labels: label_names.iter().cloned().zip(device.label_values.iter().cloned()).collect(),
+            label_values: device.label_values.clone(),
+            adapter_index: label_names.iter().position(|name| name == ADAPTER_LABEL),
+            query_control: new_query_control(query_strategy, device.update_interval),
             last_values: None,
             last_values_time: Instant::now(),
+            smoothed: HashMap::new(),
         }
-    );
+    )
 }
 struct PeripheralQueryControl {
-    metrics: Rc<CustomMetrics>,
+    serial: u32,
+    units: Units,
+    smoothing: Smoothing,
+    deadband: Deadband,
+    metrics: Arc<CustomMetrics>,
+    sinks: Sinks,
+    labels: BTreeMap<String, String>,
     label_values: Vec<String>,
+    /// of the adapter label in `label_values`
+    adapter_index: Option<usize>,
     query_control: Box<dyn QueryControl>,
-    update_interval: Duration,
+    stale_after: Duration,
+    stale_grace: Duration,
+    staleness: Staleness,
 
     last_values: Option<SensorValues>,
     last_values_time: Instant,
+    /// moving averages by `Smoothing` field, unrounded
+    smoothed: HashMap<&'static str, f64>,
 }
 
 impl PeripheralControl<SensorValues> for PeripheralQueryControl {
@@ -41,55 +83,281 @@ impl PeripheralControl<SensorValues> for PeripheralQueryControl {
     }
 
     fn update(&mut self, now: Instant, values: &SensorValues) {
+        // the gauges still get the values as they are
         let changed = match &self.last_values {
             None => true,
-            Some(last_values) => last_values != values,
+            Some(last_values) => changed(&self.deadband, last_values, values),
         };
 
         self.last_values = Some((*values).clone());
         self.last_values_time = now;
+        if self.staleness != Staleness::Fresh {
+            info!("peripheral {:?} is back", self.label_values);
+            self.staleness = Staleness::Fresh;
+        }
         self.query_control.update(now, changed);
+        if let Some((start, end)) = self.query_control.expected_interval() {
+            self.metrics.gauge_expected_interval
+                .with_label_values(&[&self.serial.to_string()])
+                .set((end - start).as_secs_f64());
+        }
+        let time = SystemTime::now();
+        let reading = DeviceReading::new(self.serial, &self.labels, time, values);
+        for sink in self.sinks.iter() {
+            sink.publish(&reading);
+        }
 
+        info!("device {:?}, payload v{}, {:?}", self.label_values, values.version, values);
+        // sinks get the readings as they are, only the gauges are smoothed
+        let values = &self.smooth(values);
         let label_values: Vec<&str> = as_slice(&self.label_values);
-        info!("device {:?}, {:?}", label_values, values);
-        self.metrics.gauge_humidity.with_label_values(&label_values).set(values.humidity as f64);
-        self.metrics.gauge_temp.with_label_values(&label_values).set(values.temp as f64);
-        self.metrics.gauge_atm.with_label_values(&label_values).set(values.atm as f64);
-        self.metrics.gauge_radon_short.with_label_values(&label_values).set(values.radon_short as i64);
-        self.metrics.gauge_radon_long.with_label_values(&label_values).set(values.radon_long as i64);
-        self.metrics.gauge_co2.with_label_values(&label_values).set(values.co2 as i64);
-        self.metrics.gauge_voc.with_label_values(&label_values).set(values.voc as i64);
+        for (gauge, exported, value) in sensor_gauges(&self.metrics, self.units).iter() {
+            if *exported {
+                gauge.set(&label_values, value(values));
+            }
+        }
+        let last_seen = time.duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64());
+        self.metrics.gauge_last_seen.with_label_values(&label_values).set(last_seen);
+        self.metrics.gauge_stale.with_label_values(&label_values).set(0);
+        if changed {
+            self.metrics.gauge_last_change.with_label_values(&label_values).set(last_seen);
+        }
+    }
+
+    fn expected_interval(&self) -> Option<(Instant, Instant)> {
+        self.query_control.expected_interval()
     }
 
-    fn remove_metric_if_stale(&self, now: Instant) {
-        if now.duration_since(self.last_values_time) > self.update_interval*2 {
-            let label_values: Vec<&str> = as_slice(&self.label_values);
-            warn!("peripheral {:?} has stale values, removing from metrics", label_values);
-            self.metrics.gauge_humidity.remove_label_values(&label_values);
-            self.metrics.gauge_temp.remove_label_values(&label_values);
-            self.metrics.gauge_atm.remove_label_values(&label_values);
-            self.metrics.gauge_radon_short.remove_label_values(&label_values);
-            self.metrics.gauge_radon_long.remove_label_values(&label_values);
-            self.metrics.gauge_co2.remove_label_values(&label_values);
-            self.metrics.gauge_voc.remove_label_values(&label_values);
+    fn restore_expected_interval(&mut self, now: Instant, expected_interval: (Instant, Instant)) {
+        self.query_control.restore_expected_interval(now, expected_interval);
+    }
+
+    fn set_adapter(&mut self, adapter: &str) {
+        let index = match self.adapter_index {
+            Some(index) if self.label_values[index] != adapter => index,
+            _ => return,
+        };
+        self.remove_device_series();
+
+        self.label_values[index] = adapter.to_string();
+        self.labels.insert(String::from(ADAPTER_LABEL), adapter.to_string());
+    }
+
+    fn remove_metric_if_stale(&mut self, now: Instant) {
+        let age = now.duration_since(self.last_values_time);
+        let label_values: Vec<&str> = as_slice(&self.label_values);
+        if age > self.stale_after + self.stale_grace {
+            if self.staleness != Staleness::Removed {
+                // last seen and last change stay, they are what staleness alerts are based on
+                warn!("peripheral {:?} stale for {:?}, removed from metrics", label_values, age);
+                self.remove_sensor_gauges(&label_values);
+                let _ = self.metrics.gauge_stale.remove_label_values(&label_values);
+                self.staleness = Staleness::Removed;
+            }
+        } else if age > self.stale_after && self.staleness == Staleness::Fresh {
+            warn!("peripheral {:?} went stale, keeping its values for another {:?}", label_values, self.stale_grace);
+            self.metrics.gauge_stale.with_label_values(&label_values).set(1);
+            self.staleness = Staleness::Stale;
         }
     }
+
+    fn remove_metrics(&self) {
+        self.remove_device_series();
+        let serial = self.serial.to_string();
+        let _ = self.metrics.gauge_rssi.remove_label_values(&[&serial]);
+        let _ = self.metrics.gauge_expected_interval.remove_label_values(&[&serial]);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Staleness {
+    Fresh,
+    /// values kept for the grace period, marked stale
+    Stale,
+    Removed,
+}
+
+impl PeripheralQueryControl {
+    fn smooth(&mut self, values: &SensorValues) -> SensorValues {
+        let smoothing = self.smoothing;
+        SensorValues {
+            humidity: self.ema("humidity", smoothing.humidity, values.humidity.map(f64::from)).map(|v| v as f32),
+            temp: self.ema("temperature", smoothing.temperature, values.temp.map(f64::from)).map(|v| v as f32),
+            atm: self.ema("atm_pressure", smoothing.atm_pressure, values.atm.map(f64::from)).map(|v| v as f32),
+            radon_short: self.ema("radon_short", smoothing.radon_short, values.radon_short.map(f64::from))
+                .map(|v| v.round() as u16),
+            radon_long: self.ema("radon_long", smoothing.radon_long, values.radon_long.map(f64::from))
+                .map(|v| v.round() as u16),
+            co2: self.ema("co2", smoothing.co2, values.co2.map(f64::from)).map(|v| v.round() as u16),
+            voc: self.ema("voc", smoothing.voc, values.voc.map(f64::from)).map(|v| v.round() as u16),
+            ..values.clone()
+        }
+    }
+
+    /// the value as is if it is not smoothed
+    fn ema(&mut self, key: &'static str, alpha: Option<f64>, value: Option<f64>) -> Option<f64> {
+        let (alpha, value) = match (alpha, value) {
+            (Some(alpha), Some(value)) => (alpha, value),
+            _ => return value,
+        };
+        let smoothed = match self.smoothed.get(key) {
+            Some(previous) => alpha * value + (1.0 - alpha) * previous,
+            None => value,
+        };
+        self.smoothed.insert(key, smoothed);
+        Some(smoothed)
+    }
+
+    /// all of them, whatever the device's units, so a unit switched off does not leave its series behind
+    fn remove_sensor_gauges(&self, label_values: &[&str]) {
+        for (gauge, _, _) in sensor_gauges(&self.metrics, self.units).iter() {
+            gauge.remove(label_values);
+        }
+    }
+
+    /// every series labelled with the device's current label values
+    fn remove_device_series(&self) {
+        let label_values: Vec<&str> = as_slice(&self.label_values);
+        self.remove_sensor_gauges(&label_values);
+        let _ = self.metrics.gauge_last_seen.remove_label_values(&label_values);
+        let _ = self.metrics.gauge_last_change.remove_label_values(&label_values);
+        let _ = self.metrics.gauge_stale.remove_label_values(&label_values);
+    }
 }
 
-fn as_slice(vec: &Vec<String>) -> Vec<&str> {
+/// whether any measured value moved further than its deadband, or changed at all without one;
+/// like `SensorValues::eq`, the battery draining does not make a reading new
+fn changed(deadband: &Deadband, last: &SensorValues, values: &SensorValues) -> bool {
+    let moved = |threshold: Option<f64>, last: Option<f64>, value: Option<f64>| match (threshold, last, value) {
+        (Some(threshold), Some(last), Some(value)) => (value - last).abs() > threshold,
+        _ => last != value,
+    };
+    let float = |value: Option<f32>| value.map(f64::from);
+    let int = |value: Option<u16>| value.map(f64::from);
+    moved(deadband.humidity, float(last.humidity), float(values.humidity))
+        || moved(deadband.temperature, float(last.temp), float(values.temp))
+        || moved(deadband.atm_pressure, float(last.atm), float(values.atm))
+        || moved(deadband.radon_short, int(last.radon_short), int(values.radon_short))
+        || moved(deadband.radon_long, int(last.radon_long), int(values.radon_long))
+        || moved(deadband.co2, int(last.co2), int(values.co2))
+        || moved(deadband.voc, int(last.voc), int(values.voc))
+}
+
+/// A per device sensor gauge, `None` when the metric is disabled.
+enum SensorGauge<'a> {
+    Float(&'a Option<GaugeVec>),
+    Int(&'a Option<IntGaugeVec>),
+}
+
+impl SensorGauge<'_> {
+    fn set(&self, label_values: &[&str], value: Option<f64>) {
+        match self {
+            SensorGauge::Float(gauge) => set_gauge(gauge, label_values, value),
+            // only ever holds whole numbers
+            SensorGauge::Int(gauge) => set_gauge(gauge, label_values, value.map(|value| value as i64)),
+        }
+    }
+
+    fn remove(&self, label_values: &[&str]) {
+        match self {
+            SensorGauge::Float(gauge) => remove_gauge(gauge, label_values),
+            SensorGauge::Int(gauge) => remove_gauge(gauge, label_values),
+        }
+    }
+}
+
+/// a gauge's value in a reading
+type SensorValue = fn(&SensorValues) -> Option<f64>;
+
+/// Every per device sensor gauge, whether it is exported in the device's units, and its value in a reading.
+/// `update` sets and removal clears exactly these, a new gauge only needs adding here.
+fn sensor_gauges(metrics: &CustomMetrics, units: Units) -> [(SensorGauge<'_>, bool, SensorValue); 16] {
+    use SensorGauge::{Float, Int};
+    let ratio = units.humidity == HumidityUnit::Ratio;
+    let fahrenheit = units.temperature == TemperatureUnit::Fahrenheit;
+    let pcil = units.radon == RadonUnit::PicocuriePerLiter;
+    [
+        (Float(&metrics.gauge_humidity), true, |values| values.humidity.map(f64::from)),
+        (Float(&metrics.gauge_humidity_ratio), ratio, SensorValues::humidity_ratio),
+        (Float(&metrics.gauge_temp), true, |values| values.temp.map(f64::from)),
+        (Float(&metrics.gauge_temp_fahrenheit), fahrenheit, |values| values.temp_fahrenheit().map(f64::from)),
+        (Float(&metrics.gauge_atm), true, |values| values.atm.map(f64::from)),
+        (Float(&metrics.gauge_dew_point), true, |values| values.dew_point().map(f64::from)),
+        (Float(&metrics.gauge_absolute_humidity), true, |values| values.absolute_humidity().map(f64::from)),
+        (Int(&metrics.gauge_radon_short), true, |values| values.radon_short.map(f64::from)),
+        (Int(&metrics.gauge_radon_long), true, |values| values.radon_long.map(f64::from)),
+        (Float(&metrics.gauge_radon_short_pcil), pcil, SensorValues::radon_short_pcil),
+        (Float(&metrics.gauge_radon_long_pcil), pcil, SensorValues::radon_long_pcil),
+        (Int(&metrics.gauge_co2), true, |values| values.co2.map(f64::from)),
+        (Int(&metrics.gauge_voc), true, |values| values.voc.map(f64::from)),
+        (Int(&metrics.gauge_co2_level), true, |values| values.co2_level().map(|level| level.severity() as f64)),
+        (Int(&metrics.gauge_voc_level), true, |values| values.voc_level().map(|level| level.severity() as f64)),
+        (Int(&metrics.gauge_battery), true, |values| values.battery.map(f64::from)),
+    ]
+}
+
+/// leaves the gauge as is without a value or if the metric is disabled
+fn set_gauge<P: Atomic>(gauge: &Option<GenericGaugeVec<P>>, label_values: &[&str], value: Option<P::T>) {
+    if let (Some(gauge), Some(value)) = (gauge, value) {
+        gauge.with_label_values(label_values).set(value);
+    }
+}
+
+fn remove_gauge<P: Atomic>(gauge: &Option<GenericGaugeVec<P>>, label_values: &[&str]) {
+    if let Some(gauge) = gauge {
+        let _ = gauge.remove_label_values(label_values);
+    }
+}
+
+fn as_slice(vec: &[String]) -> Vec<&str> {
     vec.iter().map(|x| &**x).collect()
 }
 
-pub trait QueryControl {
+pub trait QueryControl: Send {
     fn should_query(&self, now: Instant) -> bool;
     fn update(&mut self, now: Instant, changed: bool);
+    /// the interval the next value change is expected in, if learned yet
+    fn expected_interval(&self) -> Option<(Instant, Instant)>;
+    /// resumes from an interval learned earlier, e.g. before a restart
+    fn restore_expected_interval(&mut self, now: Instant, expected_interval: (Instant, Instant));
 }
 
-pub fn new_query_control(update_interval: Duration) -> Box<dyn QueryControl> {
-    return Box::new(BinarySearchQueryControl {
-        sensor_update_interval: update_interval,
-        expected_interval: None,
-    })
+pub fn new_query_control(strategy: QueryStrategy, update_interval: Duration) -> Box<dyn QueryControl> {
+    match strategy {
+        QueryStrategy::Adaptive => Box::new(BinarySearchQueryControl {
+            sensor_update_interval: update_interval,
+            expected_interval: None,
+        }),
+        QueryStrategy::Fixed(query_interval) => Box::new(FixedIntervalQueryControl {
+            query_interval,
+            last_query: None,
+        }),
+    }
+}
+
+/// reads once per interval, learns nothing
+struct FixedIntervalQueryControl {
+    query_interval: Duration,
+    last_query: Option<Instant>,
+}
+
+impl QueryControl for FixedIntervalQueryControl {
+    fn should_query(&self, now: Instant) -> bool {
+        match self.last_query {
+            None => true,
+            Some(last_query) => now >= last_query + self.query_interval,
+        }
+    }
+
+    fn update(&mut self, now: Instant, _changed: bool) {
+        self.last_query = Some(now);
+    }
+
+    fn expected_interval(&self) -> Option<(Instant, Instant)> {
+        None
+    }
+
+    fn restore_expected_interval(&mut self, _now: Instant, _expected_interval: (Instant, Instant)) {}
 }
 
 struct BinarySearchQueryControl {
@@ -99,11 +367,9 @@ struct BinarySearchQueryControl {
 
 impl QueryControl for BinarySearchQueryControl {
     fn should_query(&self, now: Instant) -> bool {
-        return match self.expected_interval {
+        match self.expected_interval {
             None => true,
-            Some(expected_interval) => {
-                return now > Self::next_query_interval(expected_interval)
-            }
+            Some(expected_interval) => now > Self::next_query_interval(expected_interval),
         }
     }
 
@@ -131,10 +397,10 @@ impl QueryControl for BinarySearchQueryControl {
                     if changed {
                         // we expect the value to change when past the expected interval
                         // in this case we can not slice the interval, so simply advance
-                        let mut new_expected_interval = expected_interval.clone();
+                        let mut new_expected_interval = expected_interval;
                         while new_expected_interval.1 < now {
-                            new_expected_interval.0 = new_expected_interval.0 + self.sensor_update_interval;
-                            new_expected_interval.1 = new_expected_interval.1 + self.sensor_update_interval;
+                            new_expected_interval.0 += self.sensor_update_interval;
+                            new_expected_interval.1 += self.sensor_update_interval;
                         }
 
                         self.expected_interval = Some(new_expected_interval);
@@ -165,9 +431,26 @@ impl QueryControl for BinarySearchQueryControl {
             }
         }
     }
+
+    fn expected_interval(&self) -> Option<(Instant, Instant)> {
+        self.expected_interval
+    }
+
+    fn restore_expected_interval(&mut self, now: Instant, expected_interval: (Instant, Instant)) {
+        // the sensor keeps its phase while we are down, so only the cycle needs catching up
+        self.expected_interval = Some(self.advance_past(now, expected_interval));
+    }
 }
 
 impl BinarySearchQueryControl {
+    fn advance_past(&self, now: Instant, mut expected_interval: (Instant, Instant)) -> (Instant, Instant) {
+        while expected_interval.1 < now {
+            expected_interval.0 += self.sensor_update_interval;
+            expected_interval.1 += self.sensor_update_interval;
+        }
+        expected_interval
+    }
+
     fn next_query_interval(expected_interval: (Instant, Instant)) -> Instant {
         if expected_interval.1 - expected_interval.0 <= Duration::from_secs(10) {
             expected_interval.1
@@ -179,22 +462,30 @@ impl BinarySearchQueryControl {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
     use std::time::{Duration, Instant};
-    use rand;
     use rand::{Rng, SeedableRng};
     use rand_chacha::ChaCha8Rng;
 
+    use super::PeripheralControl;
+    use crate::config::{
+        Deadband, DeviceConfig, HumidityUnit, MetricsConfig, QueryStrategy, RadonUnit, Smoothing, TemperatureUnit,
+        Units,
+    };
+    use crate::metrics::CustomMetrics;
+    use crate::sensor::{SensorModel, SensorValues};
+
     #[test]
     fn query_control_learns_sensor_update_times() {
         let mut rng = ChaCha8Rng::from_seed(Default::default());
         let mut now = Instant::now();
 
-        for test in 0..1000 {
-            let mut times = super::new_query_control(Duration::from_secs(5 * 60));
+        for _ in 0..1000 {
+            let mut times = super::new_query_control(QueryStrategy::Adaptive, Duration::from_secs(5 * 60));
             let mut update_time = now + Duration::from_secs(rng.gen_range(0..300));
             let mut hits_streak = 0;
 
-            for i in 0..60 * 60 {
+            for _ in 0..60 * 60 {
                 if times.should_query(now) {
                     if now > update_time && (now.duration_since(update_time)) <= Duration::from_secs(15) {
                         hits_streak += 1;
@@ -204,15 +495,210 @@ mod tests {
 
                     times.update(now, now > update_time);
                     while now > update_time {
-                        update_time = update_time + Duration::from_secs(300);
+                        update_time += Duration::from_secs(300);
                     }
                 }
 
-                now = now + Duration::from_secs(1);
+                now += Duration::from_secs(1);
             }
 
-            assert_eq!(hits_streak >= 6, true, "last 6 queries should be spot on");
+            assert!(hits_streak >= 6, "last 6 queries should be spot on");
+        }
+    }
+
+    #[test]
+    fn restored_interval_catches_up_with_sensor_cycle() {
+        let now = Instant::now() + Duration::from_secs(3600);
+        let mut times = super::new_query_control(QueryStrategy::Adaptive, Duration::from_secs(300));
+
+        let saved = (now - Duration::from_secs(1180), now - Duration::from_secs(1160));
+        times.restore_expected_interval(now, saved);
+
+        let restored = times.expected_interval().unwrap();
+        assert_eq!(restored, (now + Duration::from_secs(20), now + Duration::from_secs(40)));
+        assert!(!times.should_query(now));
+    }
+
+    #[test]
+    fn fixed_interval_queries_on_schedule() {
+        let now = Instant::now();
+        let mut times = super::new_query_control(QueryStrategy::Fixed(Duration::from_secs(60)), Duration::from_secs(300));
+
+        assert!(times.should_query(now));
+        times.update(now, true);
+        assert!(!times.should_query(now + Duration::from_secs(59)));
+        assert!(times.should_query(now + Duration::from_secs(60)));
+        assert_eq!(times.expected_interval(), None);
+    }
+
+    fn control(units: Units, smoothing: Smoothing) -> (Box<dyn PeripheralControl<SensorValues>>, Arc<CustomMetrics>) {
+        let device = DeviceConfig {
+            serial: 1,
+            label_values: vec![String::from("1")],
+            update_interval: Duration::from_secs(300),
+            stale_after: Duration::from_secs(600),
+            stale_grace: Duration::from_secs(300),
+            units,
+        };
+        let (metrics, _) = CustomMetrics::new(&[String::from("serial")], &MetricsConfig::default()).unwrap();
+        let metrics = Arc::new(metrics);
+        let control = super::new_peripheral_control(
+            &device, &[String::from("serial")], QueryStrategy::Adaptive, smoothing, Deadband::default(),
+            Arc::clone(&metrics), Arc::new(Vec::new()),
+        );
+        (control, metrics)
+    }
+
+    fn values(co2: u16, voc: u16) -> SensorValues {
+        SensorValues {
+            version: 1,
+            humidity: None, temp: None, atm: None, radon_short: None, radon_long: None,
+            co2: Some(co2),
+            voc: Some(voc),
+            battery: None,
+        }
+    }
+
+    #[test]
+    fn last_change_is_only_set_by_new_values() {
+        let (mut control, metrics) = control(Units::default(), Smoothing::default());
+        let last_change = metrics.gauge_last_change.with_label_values(&["1"]);
+        let now = Instant::now();
+
+        control.update(now, &values(600, 100));
+        assert!(last_change.get() > 0.0);
+        last_change.set(0.0);
+        control.update(now, &values(600, 100));
+        assert_eq!(last_change.get(), 0.0, "the same values again");
+        control.update(now, &SensorValues { battery: Some(80), ..values(600, 100) });
+        assert_eq!(last_change.get(), 0.0, "only the battery changed");
+        control.update(now, &values(650, 100));
+        assert!(last_change.get() > 0.0);
+    }
+
+    #[test]
+    fn changes_within_the_deadband_are_not_refreshes() {
+        let deadband = Deadband { co2: Some(5.0), ..Deadband::default() };
+        let last = values(600, 100);
+
+        assert!(!super::changed(&deadband, &last, &values(605, 100)));
+        assert!(super::changed(&deadband, &last, &values(606, 100)));
+        assert!(super::changed(&deadband, &last, &values(600, 101)), "no deadband for voc");
+        assert!(super::changed(&Deadband::default(), &last, &values(601, 100)));
+        assert!(!super::changed(&Deadband::default(), &last, &values(600, 100)));
+
+        let drained = SensorValues { battery: Some(80), ..values(600, 100) };
+        assert!(!super::changed(&Deadband::default(), &last, &drained), "battery alone");
+    }
+
+    #[test]
+    fn smoothed_gauges_follow_the_moving_average() {
+        let (mut control, metrics) = control(Units::default(), Smoothing { co2: Some(0.5), ..Smoothing::default() });
+
+        let now = Instant::now();
+        for (co2, voc) in [(600, 100), (1000, 500), (600, 100)].iter() {
+            control.update(now, &values(*co2, *voc));
         }
+
+        assert_eq!(metrics.gauge_co2.as_ref().unwrap().with_label_values(&["1"]).get(), 700);
+        assert_eq!(metrics.gauge_voc.as_ref().unwrap().with_label_values(&["1"]).get(), 100, "not smoothed");
+    }
+
+    #[test]
+    fn humidity_is_also_exported_as_ratio() {
+        let units = Units { humidity: HumidityUnit::Ratio, ..Units::default() };
+        let (mut control, metrics) = control(units, Smoothing::default());
+
+        control.update(Instant::now(), &SensorValues { humidity: Some(45.5), ..values(600, 100) });
+
+        assert_eq!(metrics.gauge_humidity.as_ref().unwrap().with_label_values(&["1"]).get(), 45.5);
+        assert_eq!(metrics.gauge_humidity_ratio.as_ref().unwrap().with_label_values(&["1"]).get(), 0.455);
+    }
+
+    #[test]
+    fn fields_a_model_lacks_are_not_exported() {
+        let units = Units { radon: RadonUnit::PicocuriePerLiter, ..Units::default() };
+        let (mut control, metrics) = control(units, Smoothing::default());
+        let data = vec![0x03, 0x00, 0x46, 0x73, 0xc4, 0x27, 0x18, 0x10, 0x78, 0x00];
+        let mini = SensorValues::from_vec(SensorModel::WaveMini, data).unwrap();
+
+        control.update(Instant::now(), &mini);
+
+        assert_eq!(metrics.gauge_voc.as_ref().unwrap().with_label_values(&["1"]).get(), 120);
+        // rather than a misleading 0
+        assert!(metrics.gauge_radon_short.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_radon_short_pcil.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_co2.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_co2_level.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_atm.as_ref().unwrap().remove_label_values(&["1"]).is_err());
     }
 
+    #[test]
+    fn switching_adapters_moves_the_series() {
+        let names = vec![String::from("serial"), String::from("adapter")];
+        let device = DeviceConfig {
+            serial: 1,
+            label_values: vec![String::from("1"), String::new()],
+            update_interval: Duration::from_secs(300),
+            stale_after: Duration::from_secs(600),
+            stale_grace: Duration::from_secs(300),
+            units: Units::default(),
+        };
+        let metrics = Arc::new(CustomMetrics::new(&names, &MetricsConfig::default()).unwrap().0);
+        let mut control = super::new_peripheral_control(
+            &device, &names, QueryStrategy::Adaptive, Smoothing::default(), Deadband::default(),
+            Arc::clone(&metrics), Arc::new(Vec::new()),
+        );
+        let co2 = metrics.gauge_co2.as_ref().unwrap();
+
+        control.set_adapter("hci0");
+        control.update(Instant::now(), &values(600, 100));
+        control.set_adapter("hci1");
+        control.update(Instant::now(), &values(650, 100));
+
+        assert!(co2.remove_label_values(&["1", "hci0"]).is_err(), "dropped with the old adapter");
+        assert_eq!(co2.with_label_values(&["1", "hci1"]).get(), 650);
+    }
+
+    #[test]
+    fn stale_values_are_marked_before_they_are_dropped() {
+        let (mut control, metrics) = control(Units::default(), Smoothing::default());
+        let co2 = metrics.gauge_co2.as_ref().unwrap();
+        let now = Instant::now();
+        control.update(now, &values(600, 100));
+
+        control.remove_metric_if_stale(now + Duration::from_secs(601));
+        assert_eq!(co2.with_label_values(&["1"]).get(), 600, "kept for the grace period");
+        assert_eq!(metrics.gauge_stale.with_label_values(&["1"]).get(), 1);
+
+        control.remove_metric_if_stale(now + Duration::from_secs(901));
+        assert!(co2.remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_stale.remove_label_values(&["1"]).is_err());
+
+        control.update(now + Duration::from_secs(1000), &values(650, 100));
+        assert_eq!(metrics.gauge_stale.with_label_values(&["1"]).get(), 0);
+    }
+
+    #[test]
+    fn derived_gauges_are_dropped_with_the_values() {
+        let units = Units {
+            radon: RadonUnit::PicocuriePerLiter,
+            temperature: TemperatureUnit::Fahrenheit,
+            humidity: HumidityUnit::Ratio,
+        };
+        let (mut control, metrics) = control(units, Smoothing::default());
+        let now = Instant::now();
+        let full = SensorValues { humidity: Some(45.5), temp: Some(21.0), radon_short: Some(40), ..values(900, 100) };
+        control.update(now, &full);
+        assert_eq!(metrics.gauge_co2_level.as_ref().unwrap().with_label_values(&["1"]).get(), 1);
+
+        control.remove_metric_if_stale(now + Duration::from_secs(901));
+
+        assert!(metrics.gauge_dew_point.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_absolute_humidity.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_humidity_ratio.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_temp_fahrenheit.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_radon_short_pcil.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+        assert!(metrics.gauge_co2_level.as_ref().unwrap().remove_label_values(&["1"]).is_err());
+    }
 }
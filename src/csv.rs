@@ -0,0 +1,137 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{TimeZone, Utc};
+use log::{info, warn};
+
+use crate::config::CsvConfig;
+use crate::readings::DeviceReading;
+use crate::sink::ReadingSink;
+
+const VALUE_COLUMNS: [&str; 8] =
+    ["humidity", "temperature", "atm_pressure", "radon_short", "radon_long", "co2", "voc", "battery"];
+
+struct CsvSink {
+    path: String,
+    /// without `serial`, it has its own column
+    label_names: Vec<String>,
+    file: Mutex<File>,
+}
+
+/// Appends every reading to a CSV file as a row of time, serial, a column per label and one per value.
+/// A file written with other columns, e.g. before the label names changed, is moved aside to `<path>.<timestamp>`
+/// rather than appended to.
+pub fn new_csv_sink(config: &CsvConfig, label_names: &[String]) -> io::Result<Box<dyn ReadingSink>> {
+    let label_names: Vec<String> = label_names.iter().filter(|name| *name != "serial").cloned().collect();
+    let header = header(&label_names);
+
+    let existing = match File::open(&config.path) {
+        Ok(file) => BufReader::new(file).lines().next().transpose()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    if let Some(existing) = existing.as_ref().filter(|existing| **existing != header) {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let aside = format!("{}.{}", config.path, seconds);
+        info!("{} has other columns ({}), moved to {}", config.path, existing, aside);
+        fs::rename(&config.path, &aside)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+    if existing.as_ref() != Some(&header) {
+        writeln!(file, "{}", header)?;
+    }
+    Ok(Box::new(CsvSink { path: config.path.clone(), label_names, file: Mutex::new(file) }))
+}
+
+impl ReadingSink for CsvSink {
+    fn publish(&self, reading: &DeviceReading) {
+        let line = row(&self.label_names, reading);
+        // a whole row in one write, so rows of concurrent writers do not interleave
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("failed to write reading for {} to {}: {}", reading.serial, self.path, err);
+        }
+    }
+}
+
+fn header(label_names: &[String]) -> String {
+    let mut columns = vec![String::from("time"), String::from("serial")];
+    columns.extend(label_names.iter().map(|name| field(name)));
+    columns.extend(VALUE_COLUMNS.iter().map(|name| name.to_string()));
+    columns.join(",")
+}
+
+/// empty fields for values the device does not measure
+fn row(label_names: &[String], reading: &DeviceReading) -> String {
+    let time = Utc.timestamp_opt(reading.last_seen as i64, 0).single()
+        .map_or_else(String::new, |time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string());
+    let mut fields = vec![time, reading.serial.to_string()];
+    for name in label_names {
+        fields.push(field(reading.labels.get(name).map_or("", String::as_str)));
+    }
+    let values = &reading.values;
+    let float = |value: Option<f32>| {
+        value.filter(|value| value.is_finite()).map_or_else(String::new, |value| value.to_string())
+    };
+    let int = |value: Option<u16>| value.map_or_else(String::new, |value| value.to_string());
+    fields.extend(vec![
+        float(values.humidity),
+        float(values.temp),
+        float(values.atm),
+        int(values.radon_short),
+        int(values.radon_long),
+        int(values.co2),
+        int(values.voc),
+        values.battery.map_or_else(String::new, |value| value.to_string()),
+    ]);
+    format!("{}\n", fields.join(","))
+}
+
+/// quoted if it holds a separator, a quote or a line break
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::new_csv_sink;
+    use crate::config::CsvConfig;
+    use crate::readings::DeviceReading;
+
+    fn reading(room: &str) -> DeviceReading {
+        DeviceReading::fixture(2930025667, &[("room", room)])
+    }
+
+    #[test]
+    fn rows_are_appended_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("airthing-csv-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("readings.csv").to_str().unwrap().to_string();
+        let config = CsvConfig { path: path.clone() };
+        let names = vec![String::from("serial"), String::from("room")];
+
+        new_csv_sink(&config, &names).unwrap().publish(&reading("Study, upstairs"));
+        new_csv_sink(&config, &names).unwrap().publish(&reading("Study"));
+        let text = fs::read_to_string(&path).unwrap();
+
+        let renamed = vec![String::from("serial"), String::from("location")];
+        new_csv_sink(&config, &renamed).unwrap();
+        let files = fs::read_dir(&dir).unwrap().count();
+        let restarted = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(text, "time,serial,room,humidity,temperature,atm_pressure,radon_short,radon_long,co2,voc,battery\n\
+            2020-09-13T12:26:40Z,2930025667,\"Study, upstairs\",45.5,21.25,,40,,600,,\n\
+            2020-09-13T12:26:40Z,2930025667,Study,45.5,21.25,,40,,600,,\n");
+        assert_eq!(files, 2, "the file with other columns is moved aside");
+        assert!(restarted.starts_with("time,serial,location,"));
+    }
+}
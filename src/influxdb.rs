@@ -0,0 +1,142 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use log::{debug, warn};
+
+use crate::config::InfluxDbConfig;
+use crate::readings::DeviceReading;
+use crate::sink::ReadingSink;
+
+struct InfluxDbSink {
+    client: Client<HttpConnector>,
+    write_url: String,
+    token: String,
+    measurement: String,
+}
+
+/// Writes every reading to an InfluxDB v2 bucket in the background, failed writes are logged and dropped.
+pub fn new_influxdb_sink(config: &InfluxDbConfig) -> Box<dyn ReadingSink> {
+    let write_url = format!(
+        "{}/api/v2/write?org={}&bucket={}&precision=s",
+        config.url.trim_end_matches('/'),
+        percent_encode(&config.org),
+        percent_encode(&config.bucket),
+    );
+
+    Box::new(InfluxDbSink {
+        client: Client::new(),
+        write_url,
+        token: config.token.clone(),
+        measurement: config.measurement.clone(),
+    })
+}
+
+impl ReadingSink for InfluxDbSink {
+    fn publish(&self, reading: &DeviceReading) {
+        let line = match line_protocol(&self.measurement, reading) {
+            Some(line) => line,
+            None => return,
+        };
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.write_url)
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(Body::from(line));
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("failed to build influxdb write for {}: {}", reading.serial, err);
+                return;
+            }
+        };
+
+        let client = self.client.clone();
+        let serial = reading.serial;
+        tokio::spawn(async move {
+            match client.request(request).await {
+                Ok(response) if response.status().is_success() => debug!("wrote reading for {} to influxdb", serial),
+                Ok(response) => warn!("influxdb rejected the reading for {}: {}", serial, response.status()),
+                Err(err) => warn!("failed to write reading for {} to influxdb: {}", serial, err),
+            }
+        });
+    }
+}
+
+/// one line, tagged with the non-empty labels, `None` if there are no values to write
+fn line_protocol(measurement: &str, reading: &DeviceReading) -> Option<String> {
+    let values = &reading.values;
+    let mut fields = Vec::new();
+    let floats = [("humidity", values.humidity), ("temp", values.temp), ("atm", values.atm)];
+    for (name, value) in floats.iter() {
+        if let Some(value) = value.filter(|value| value.is_finite()) {
+            fields.push(format!("{}={}", name, value));
+        }
+    }
+    let integers = [
+        ("radon_short", values.radon_short),
+        ("radon_long", values.radon_long),
+        ("co2", values.co2),
+        ("voc", values.voc),
+        ("battery", values.battery.map(u16::from)),
+    ];
+    for (name, value) in integers.iter() {
+        if let Some(value) = value {
+            fields.push(format!("{}={}i", name, value));
+        }
+    }
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut line = escape(measurement, &[',', ' ']);
+    for (name, value) in reading.labels.iter().filter(|(_, value)| !value.is_empty()) {
+        line.push_str(&format!(",{}={}", escape(name, &[',', '=', ' ']), escape(value, &[',', '=', ' '])));
+    }
+    Some(format!("{} {} {}", line, fields.join(","), reading.last_seen))
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// for query parameters, leaves only unreserved characters as they are
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line_protocol, percent_encode};
+    use crate::readings::DeviceReading;
+
+    #[test]
+    fn readings_are_written_as_line_protocol() {
+        let mut reading = DeviceReading::fixture(2930025667, &[("room", "Study Room"), ("floor", "")]);
+        reading.values.co2 = None;
+        reading.values.battery = Some(90);
+
+        assert_eq!(
+            line_protocol("airthing", &reading).unwrap(),
+            "airthing,room=Study\\ Room,serial=2930025667 \
+                humidity=45.5,temp=21.25,radon_short=40i,battery=90i 1600000000",
+        );
+    }
+
+    #[test]
+    fn org_and_bucket_are_encoded() {
+        assert_eq!(percent_encode("my org/home"), "my%20org%2Fhome");
+    }
+}
@@ -1,21 +1,179 @@
-pub fn init_logger() -> anyhow::Result<(), log::SetLoggerError>{
-    return fern::Dispatch::new()
-        // Perform allocation-free log formatting
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        // Add blanket level filter -
-        .level(log::LevelFilter::Info)
-        // - and per-module overrides
-        .level_for("airthing", log::LevelFilter::Info)
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+
+/// How each log line is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `[date][time][target][level] message`
+    Pretty,
+    /// one json object per line, for log aggregation
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("unknown log format {}, expected pretty or json", format)),
+        }
+    }
+}
+
+/// Where to log besides stdout.
+pub struct LogFile {
+    pub path: PathBuf,
+    /// size in bytes after which the file is rotated
+    pub max_size: u64,
+    /// rotated files to keep, as `<path>.1` (newest) up to `<path>.<keep>`
+    pub keep: usize,
+}
+
+pub fn init_logger(level: log::LevelFilter, format: LogFormat, file: Option<LogFile>) -> anyhow::Result<()> {
+    let dispatch = match format {
+        LogFormat::Pretty => fern::Dispatch::new()
+            // Perform allocation-free log formatting
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "{}[{}][{}] {}",
+                    chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                    record.target(),
+                    record.level(),
+                    message
+                ))
+            }),
+        LogFormat::Json => fern::Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!("{}", json_line(record, message)))
+            }),
+    };
+
+    let mut dispatch = dispatch
+        // Add blanket level filter, dependencies log at the same level, btleplug's trace helps with BLE issues
+        .level(level)
         // Output to stdout, files, and other Dispatch configurations
-        .chain(std::io::stdout())
-        // Apply globally
-        .apply();
+        .chain(std::io::stdout());
+    if let Some(file) = file {
+        let rotating = RotatingFile::open(file.path.clone(), file.max_size, file.keep)
+            .with_context(|| format!("failed to open log file {}", file.path.display()))?;
+        dispatch = dispatch.chain(Box::new(rotating) as Box<dyn Write + Send>);
+    }
+
+    // Apply globally
+    dispatch.apply()?;
+    Ok(())
+}
+
+/// Appends to a file, shifting it to `<path>.1`, `<path>.1` to `<path>.2` and so on once it outgrows `max_size`.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path, max_size, keep, file, size })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.keep).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    /// fern flushes after every record, rotating here never splits a line
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.size >= self.max_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+fn json_line(record: &log::Record, message: &std::fmt::Arguments) -> serde_json::Value {
+    serde_json::json!({
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use super::{json_line, RotatingFile};
+
+    #[test]
+    fn json_line_has_level_target_and_message() {
+        let args = format_args!("peripheral {} queried", 42);
+        let record = log::Record::builder()
+            .level(log::Level::Warn)
+            .target("airthing::control")
+            .args(args)
+            .build();
+
+        let line = json_line(&record, record.args());
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "airthing::control");
+        assert_eq!(line["message"], "peripheral 42 queried");
+        assert!(line["timestamp"].is_string());
+    }
+
+    #[test]
+    fn log_file_rotates_and_keeps_the_newest() {
+        let dir = std::env::temp_dir().join(format!("airthing-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("airthing.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first line\n", "second line\n", "third line\n", "fourth\n"].iter() {
+            file.write_all(line.as_bytes()).unwrap();
+            file.flush().unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("airthing.log.1")).unwrap(), "third line\n");
+        assert_eq!(fs::read_to_string(dir.join("airthing.log.2")).unwrap(), "second line\n");
+        assert!(!dir.join("airthing.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
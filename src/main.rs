@@ -1,155 +1,1336 @@
-use std::panic;
+use std::path::Path;
+use std::fmt;
+use std::process;
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::time::{Duration, Instant};
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, Context, Result};
-use btleplug::api::{Central, Manager as _, Peripheral, ScanFilter};
-use btleplug::platform::{Adapter, Manager};
-use log::{debug, info, trace, warn};
-use tokio::time;
-use uuid::Uuid;
+use anyhow::{bail, Result};
+use futures::stream::{self, StreamExt};
+use log::{debug, error, info, trace, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::task::JoinError;
+use tokio::time::{self, MissedTickBehavior};
 
 use metrics::CustomMetrics;
-use sensor::SensorValues;
+use sensor::{ParseError, SensorModel, SensorValues};
 
-use crate::control::PeripheralControl;
+use crate::config::{Config, DeviceConfig, RetryConfig, ScanSchedule};
+use crate::connections::ConnectionLimit;
+use crate::control::{PeripheralControl, Sinks};
+use crate::mock::MockReadings;
+use crate::readings::Readings;
+use crate::server::Probes;
+use crate::sink::ReadingSink;
+use crate::source::{Advertisement, DeviceInfo, QueryError, SensorSource, Timeout};
+use crate::state::SavedInterval;
 
+mod ble;
+mod cli;
 mod control;
 mod config;
+mod connections;
+mod csv;
+#[cfg(feature = "influxdb")]
+mod influxdb;
 mod logging;
 mod metrics;
+mod mock;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod openmetrics;
+#[cfg(feature = "pushgateway")]
+mod pushgateway;
+mod readings;
 mod sensor;
+mod server;
+mod sink;
+mod source;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod state;
+mod textfile;
+mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+/// how long configured devices get to show up before they are reported missing
+const DEVICE_SEARCH_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+type PeripheralControls = HashMap<u32, Box<dyn PeripheralControl<SensorValues>>>;
+
+/// Everything needed to query devices and create controls for newly seen ones.
+struct QueryContext {
+    config: Config,
+    metrics: Arc<CustomMetrics>,
+    sinks: Sinks,
+    /// intervals learned before the last restart, applied when a device shows up again
+    saved_intervals: HashMap<u32, SavedInterval>,
+    /// held from connecting to a device until it is disconnected, across passes for devices left connected
+    connections: ConnectionLimit,
+}
 
-const SENSORVALUES_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xb42e2a68_ade7_11e4_89d3_123b93f75cba);
-const SENSORVALUES_SERVICE_UUID: Uuid = Uuid::from_u128(0xb42e1c08_ade7_11e4_89d3_123b93f75cba);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    logging::init_logger()?;
+    let mut args = cli::parse_args();
+    logging::init_logger(args.log_level, args.log_format, args.log_file.take())?;
 
-    let (devices, label_names) = config::load_device_labels();
+    let mut config = match config::load_config(&args.config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
 
-    let metrics = metrics::create_metrics(&label_names);
-    let adapter_list = start_scanning().await
-        .expect("could not set adapters up to start scanning");
+    override_config(&mut config, &args);
 
-    let mut peripheral_controls: HashMap<u32, Box<dyn PeripheralControl<SensorValues>>> = HashMap::new();
-    let metrics = Rc::new(metrics);
+    if args.check_config {
+        if let Err(err) = check_config(&config) {
+            error!("{:#}", err);
+            process::exit(1);
+        }
+        print!("{}", config_summary(&args.config_path, &config));
+        return Ok(());
+    }
 
+    let source: Box<dyn SensorSource> = match (args.mock, &args.mock_fixture) {
+        (true, Some(path)) => match MockReadings::load(path) {
+            Ok(mock) => Box::new(mock),
+            Err(err) => {
+                error!("{:#}", err);
+                process::exit(1);
+            }
+        },
+        (true, None) => Box::new(MockReadings::fixed(config.devices.keys().copied())),
+        (false, _) => match ble::new_ble_source(
+            args.adapter.as_ref(),
+            config.query_timeout,
+            config.company_ids.clone(),
+            &config.scan_start_retry(),
+        ).await {
+            Ok(source) => source,
+            Err(err) => {
+                error!("could not set adapters up to start scanning: {:#}", err);
+                process::exit(1);
+            }
+        },
+    };
+    if args.mock {
+        info!("mock mode, synthesizing readings instead of scanning");
+    }
+
+    if args.dump_raw {
+        dump_raw(&config, source.as_ref()).await;
+        return Ok(());
+    }
+
+    let shutdown = Arc::new(Notify::new());
+    let readings = Readings::default();
+    let probes = Probes::new(config.readiness, config.devices.keys().copied().collect());
+    let created = metrics::create_metrics(&config, args.listen, readings.clone(), probes.clone(), Arc::clone(&shutdown));
+    let (metrics, mut server) = match created {
+        Ok(created) => created,
+        Err(err) => {
+            error!("{:#}", err);
+            process::exit(1);
+        }
+    };
+    metrics.gauge_adapters.set(source.adapters() as i64);
+    metrics.gauge_config_last_reload.set(unix_now());
+    for serial in config.devices.keys() {
+        metrics.gauge_device_configured.with_label_values(&[&serial.to_string()]).set(1);
+    }
+    let sinks = create_sinks(&config, readings);
+    probes.set_scanning(true);
+
+    let saved_intervals = match &args.state_file {
+        Some(path) if Path::new(path).exists() => state::load_state(path).unwrap_or_else(|err| {
+            warn!("starting without saved intervals: {:?}", err);
+            HashMap::new()
+        }),
+        _ => HashMap::new(),
+    };
+
+    let mut peripheral_controls: PeripheralControls = HashMap::new();
+    let mut ctx = QueryContext {
+        connections: ConnectionLimit::new(config.max_concurrent_connections),
+        config,
+        metrics: Arc::new(metrics),
+        sinks,
+        saved_intervals,
+    };
+
+    let mut shutdown_signal = Box::pin(shutdown_signal());
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut watchdog = ScanWatchdog::default();
+    let search_deadline = Instant::now() + DEVICE_SEARCH_TIMEOUT;
+    let mut searching = true;
+    let mut server_failed = false;
+    // notifications arriving in between do not push the next pass back
+    let mut first_pass = time::Instant::now();
+    if !args.poll_on_start {
+        first_pass += ctx.config.scan_interval;
+    }
+    let mut passes = pass_interval(&ctx.config, first_pass);
     loop {
-        time::sleep(Duration::from_secs(5)).await;
-        query_peripherals(&metrics, &adapter_list, &devices, &mut peripheral_controls).await;
+        tokio::select! {
+            _ = &mut shutdown_signal => break,
+            result = &mut server => {
+                log_server_exit(result);
+                server_failed = true;
+                break;
+            }
+            _ = hangup.recv() => {
+                let schedule = (ctx.config.scan_interval, ctx.config.scan_schedule);
+                reload_config(&args, &mut ctx, &mut peripheral_controls, &probes);
+                if schedule != (ctx.config.scan_interval, ctx.config.scan_schedule) {
+                    passes = pass_interval(&ctx.config, time::Instant::now() + ctx.config.scan_interval);
+                }
+                continue;
+            }
+            (serial, values) = source.next_notification() => {
+                apply_notification(&ctx, &mut peripheral_controls, serial, values);
+                continue;
+            }
+            _ = passes.tick() => {}
+        }
+        let timer = ctx.metrics.histogram_scan_cycle.start_timer();
+        let pass = tokio::select! {
+            _ = &mut shutdown_signal => break,
+            result = &mut server => {
+                log_server_exit(result);
+                server_failed = true;
+                break;
+            }
+            pass = query_devices(&ctx, source.as_ref(), &mut peripheral_controls) => pass,
+        };
+        let took = timer.stop_and_record();
+        // a heartbeat whenever devices were queried, most passes just find them not due yet
+        if pass.read + pass.failed > 0 {
+            info!("{}, took {:.1}s", pass, took);
+        } else {
+            debug!("{}, took {:.1}s", pass, took);
+        }
+        watchdog.observe(&ctx, source.as_ref(), &probes, pass.discovered).await;
 
-        for control in peripheral_controls.values() {
+        if searching && Instant::now() >= search_deadline {
+            for serial in unmatched_serials(&ctx.config.devices, &peripheral_controls) {
+                warn!("configured device {} not seen in {:?}, is the serial right and the device in range?",
+                    serial, DEVICE_SEARCH_TIMEOUT);
+            }
+            searching = false;
+        }
+
+        for control in peripheral_controls.values_mut() {
             control.remove_metric_if_stale(Instant::now());
         }
+        if ctx.config.scan_schedule == ScanSchedule::AfterPass {
+            passes.reset();
+        }
+    }
+
+    shutdown.notify_one();
+    source.disconnect_all().await;
+    if let Some(path) = &args.state_file {
+        save_intervals(path, &peripheral_controls);
     }
+    if server_failed {
+        // rather than scanning on without anyone seeing the readings, let the supervisor restart us
+        process::exit(1);
+    }
+    Ok(())
 }
 
-async fn query_peripherals(
-    metrics: &Rc<CustomMetrics>,
-    adapter_list: &Vec<Adapter>,
-    devices_labels: &HashMap<String, Vec<String>>,
-    controls: &mut HashMap<u32, Box<dyn PeripheralControl<SensorValues>>>
-) {
-    for adapter in adapter_list.iter() {
-        let peripherals = adapter.peripherals().await;
+/// ticks when passes are due, from `start` on. With `ScanSchedule::AfterPass` it is reset after every pass,
+/// missed ticks only happen on the fixed schedule and are skipped rather than caught up on in a burst of passes
+fn pass_interval(config: &Config, start: time::Instant) -> time::Interval {
+    let mut passes = time::interval_at(start, config.scan_interval);
+    passes.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    passes
+}
 
-        if let Err(err) = peripherals {
-            warn!("Could not get peripherals: {:?}", err);
+/// the metrics server only stops on its own when it fails
+fn log_server_exit(result: Result<Result<()>, JoinError>) {
+    match result {
+        Ok(Ok(())) => error!("metrics server stopped, shutting down"),
+        Ok(Err(err)) => error!("metrics server failed, shutting down: {:#}", err),
+        Err(err) => error!("metrics server task failed, shutting down: {}", err),
+    }
+}
+
+/// what loading the config alone does not catch, without touching Bluetooth or the network
+fn check_config(config: &Config) -> Result<()> {
+    if let Some(tls) = &config.tls {
+        tls::load(tls)?;
+    }
+    if cfg!(not(feature = "mqtt")) && config.mqtt.is_some() {
+        warn!("[mqtt] is configured, but this build lacks the mqtt feature");
+    }
+    if cfg!(not(feature = "influxdb")) && config.influxdb.is_some() {
+        warn!("[influxdb] is configured, but this build lacks the influxdb feature");
+    }
+    if cfg!(not(feature = "pushgateway")) && config.pushgateway.is_some() {
+        warn!("[pushgateway] is configured, but this build lacks the pushgateway feature");
+    }
+    if cfg!(not(feature = "sqlite")) && config.sqlite.is_some() {
+        warn!("[sqlite] is configured, but this build lacks the sqlite feature");
+    }
+    Ok(())
+}
+
+fn config_summary(path: &str, config: &Config) -> String {
+    let mut summary = format!("{} is valid\nlabel names: {}\n", path, config.label_names.join(", "));
+    let mut devices: Vec<&DeviceConfig> = config.devices.values().collect();
+    devices.sort_by_key(|device| device.serial);
+    summary.push_str(&format!("{} devices:\n", devices.len()));
+    for device in devices {
+        let labels: Vec<String> = config.label_names.iter().zip(device.label_values.iter())
+            .map(|(name, value)| format!("{}={:?}", name, value))
+            .collect();
+        summary.push_str(&format!(
+            "  {} {}, update interval {}s, stale after {}s\n",
+            device.serial, labels.join(" "), device.update_interval.as_secs(), device.stale_after.as_secs(),
+        ));
+    }
+    if !config.disabled.is_empty() {
+        let mut disabled: Vec<String> = config.disabled.iter().map(u32::to_string).collect();
+        disabled.sort();
+        summary.push_str(&format!("disabled: {}\n", disabled.join(", ")));
+    }
+    if config.auto_discover {
+        summary.push_str("devices missing from the config are discovered too\n");
+    }
+    summary
+}
+
+/// prints the sensor payload of every device in range that would be queried, as hex,
+/// for adding support for models we can not parse yet
+async fn dump_raw(config: &Config, source: &dyn SensorSource) {
+    // give the first advertisements time to come in
+    time::sleep(config.scan_interval).await;
+    let advertisements = match source.scan().await {
+        Ok(scan) => scan.advertisements,
+        Err(err) => {
+            error!("Could not scan for devices: {:?}", err);
+            return;
+        }
+    };
+
+    let mut dumped = 0;
+    for Advertisement { serial, .. } in advertisements {
+        if config.device(serial).is_none() {
+            debug!("peripheral {} is not configured, skipping", serial);
             continue;
         }
+        match source.read_raw(serial).await {
+            Ok((model, data)) => {
+                println!("{}", raw_line(serial, model, &data));
+                dumped += 1;
+            }
+            Err(err) => warn!("Failed to read peripheral {}: {:?}", serial, err),
+        }
+    }
+    if dumped == 0 {
+        warn!("no devices dumped, pass --discover-all to include devices missing from the config");
+    }
+    source.disconnect_all().await;
+}
+
+fn raw_line(serial: u32, model: SensorModel, data: &[u8]) -> String {
+    let hex: Vec<String> = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{} {:?} {}", serial, model, hex.join(" "))
+}
+
+fn save_intervals(path: &str, controls: &PeripheralControls) {
+    let now = Instant::now();
+    let wall_now = SystemTime::now();
+    let intervals = controls.iter()
+        .filter_map(|(serial, control)| control.expected_interval()
+            .map(|interval| (*serial, SavedInterval::from_instants(now, wall_now, interval))))
+        .collect();
+
+    match state::save_state(path, &intervals) {
+        Ok(()) => info!("saved learned intervals of {} devices to {}", intervals.len(), path),
+        Err(err) => warn!("could not save learned intervals: {:?}", err),
+    }
+}
+
+/// completes on SIGTERM or SIGINT
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    info!("shutting down");
+}
+
+fn create_sinks(config: &Config, readings: Readings) -> Sinks {
+    let mut sinks: Vec<Box<dyn ReadingSink>> = vec![Box::new(readings)];
+
+    if let Some(path) = &config.textfile_path {
+        sinks.push(textfile::new_textfile_sink(path, &config.metrics.namespace, config.textfile_timestamps));
+    }
+
+    if let Some(csv_config) = &config.csv {
+        match csv::new_csv_sink(csv_config, &config.label_names) {
+            Ok(sink) => sinks.push(sink),
+            Err(err) => warn!("failed to open {}, readings are not logged to it: {}", csv_config.path, err),
+        }
+    }
+
+    if let Some(mqtt_config) = &config.mqtt {
+        #[cfg(feature = "mqtt")]
+        sinks.push(mqtt::new_mqtt_sink(mqtt_config));
+        #[cfg(not(feature = "mqtt"))]
+        warn!("[mqtt] is configured for {}, but this build lacks the mqtt feature", mqtt_config.host);
+    }
+
+    if let Some(influxdb_config) = &config.influxdb {
+        #[cfg(feature = "influxdb")]
+        sinks.push(influxdb::new_influxdb_sink(influxdb_config));
+        #[cfg(not(feature = "influxdb"))]
+        warn!("[influxdb] is configured for {}, but this build lacks the influxdb feature", influxdb_config.url);
+    }
+
+    if let Some(sqlite_config) = &config.sqlite {
+        #[cfg(feature = "sqlite")]
+        match sqlite::new_sqlite_sink(sqlite_config) {
+            Ok(sink) => sinks.push(sink),
+            Err(err) => warn!("failed to open {}, readings are not stored: {}", sqlite_config.path, err),
+        }
+        #[cfg(not(feature = "sqlite"))]
+        warn!("[sqlite] is configured for {}, but this build lacks the sqlite feature", sqlite_config.path);
+    }
+
+    Arc::new(sinks)
+}
+
+/// command line flags take precedence over the config file
+fn override_config(config: &mut Config, args: &cli::Args) {
+    config.auto_discover |= args.discover_all;
+    if let Some(scan_interval) = args.scan_interval {
+        config.scan_interval = scan_interval;
+    }
+}
+
+/// re-reads the config on SIGHUP, keeping the current one if the new one can't be loaded or applied
+fn reload_config(args: &cli::Args, ctx: &mut QueryContext, controls: &mut PeripheralControls, probes: &Probes) {
+    info!("reloading {}", args.config_path);
+    ctx.metrics.counter_config_reloads.inc();
+    let result = config::load_config(&args.config_path).map_err(anyhow::Error::from).and_then(|mut config| {
+        override_config(&mut config, args);
+        let configured_serials = config.devices.keys().copied().collect();
+        apply_config(ctx, controls, config)?;
+        probes.set_configured_serials(configured_serials);
+        Ok(())
+    });
+    match result {
+        Ok(()) => ctx.metrics.gauge_config_last_reload.set(unix_now()),
+        Err(err) => {
+            error!("keeping the current config: {:#}", err);
+            ctx.metrics.counter_config_reload_errors.inc();
+        }
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// Switches to a reloaded config. Devices whose settings changed get new controls, keeping the learned
+/// interval if their update interval stayed the same, devices no longer configured are dropped from the metrics.
+/// Label names are fixed once the metrics are registered, so a config adding or removing one is rejected.
+fn apply_config(ctx: &mut QueryContext, controls: &mut PeripheralControls, config: Config) -> Result<()> {
+    if config.label_names != ctx.config.label_names {
+        bail!("label names changed from {:?} to {:?}, restart to apply", ctx.config.label_names, config.label_names);
+    }
 
-        let peripherals = peripherals.unwrap();
-        if peripherals.is_empty() {
-            debug!("No peripheral devices found, skipping");
+    let previous = std::mem::replace(&mut ctx.config, config);
+    for serial in previous.devices.keys() {
+        let _ = ctx.metrics.gauge_device_configured.remove_label_values(&[&serial.to_string()]);
+    }
+    for serial in ctx.config.devices.keys() {
+        ctx.metrics.gauge_device_configured.with_label_values(&[&serial.to_string()]).set(1);
+    }
+
+    let now = Instant::now();
+    let serials: Vec<u32> = controls.keys().copied().collect();
+    for serial in serials {
+        let (before, after) = (previous.device(serial), ctx.config.device(serial));
+        if before == after {
             continue;
         }
 
-        // All peripheral devices in range.
-        trace!("discovered {} peripherals", peripherals.len());
-        for peripheral in peripherals.iter() {
-            let properties = peripheral.properties().await;
-            if let Err(err) = properties {
-                debug!("Failed to read properties from peripheral, skipping: {:?}", err);
-                continue;
+        let control = match controls.remove(&serial) {
+            Some(control) => control,
+            None => continue,
+        };
+        control.remove_metrics();
+        match after {
+            // resumes from where it was once enabled again
+            None if ctx.config.disabled.contains(&serial) => {
+                info!("device {} is disabled, dropped", serial);
+                if let Some(learned) = control.expected_interval() {
+                    ctx.saved_intervals.insert(serial, SavedInterval::from_instants(now, SystemTime::now(), learned));
+                }
+            }
+            None => info!("device {} is no longer configured, dropped", serial),
+            Some(after) => {
+                info!("device {} has new settings", serial);
+                let same_interval = before.is_some_and(|before| before.update_interval == after.update_interval);
+                let learned = control.expected_interval().filter(|_| same_interval);
+                if let (Some(control), Some(learned)) = (control_for(ctx, controls, serial), learned) {
+                    control.restore_expected_interval(now, learned);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// configured devices never seen in a scan, in order
+fn unmatched_serials(
+    devices: &HashMap<u32, DeviceConfig>,
+    controls: &PeripheralControls,
+) -> Vec<u32> {
+    let mut unmatched: Vec<u32> = devices.keys()
+        .filter(|serial| !controls.contains_key(serial))
+        .copied()
+        .collect();
+    unmatched.sort_unstable();
+    unmatched
+}
+
+/// Restarts the scan once nothing was discovered for `scan_watchdog_passes` passes in a row,
+/// BlueZ scans sometimes wedge and report nothing until restarted. The exporter is reported not live while a
+/// restart failed, until a restart succeeds or devices are discovered again.
+#[derive(Default)]
+struct ScanWatchdog {
+    empty_passes: u32,
+}
+
+impl ScanWatchdog {
+    async fn observe(&mut self, ctx: &QueryContext, source: &dyn SensorSource, probes: &Probes, discovered: usize) {
+        if discovered > 0 {
+            probes.set_scanning(true);
+        }
+        if discovered > 0 || ctx.config.scan_watchdog_passes == 0 {
+            self.empty_passes = 0;
+            return;
+        }
+
+        self.empty_passes += 1;
+        if self.empty_passes < ctx.config.scan_watchdog_passes {
+            return;
+        }
+        warn!("no devices discovered in {} passes, restarting the scan", self.empty_passes);
+        self.empty_passes = 0;
+        ctx.metrics.counter_adapter_resets.inc();
+        match source.restart_scan().await {
+            Ok(()) => probes.set_scanning(true),
+            Err(err) => {
+                error!("could not restart the scan: {:#}", err);
+                probes.set_scanning(false);
             }
+        }
+    }
+}
+
+/// What a discovery and query pass came to, for the log.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Pass {
+    discovered: usize,
+    /// configured, or auto discovered
+    matched: usize,
+    read: usize,
+    failed: usize,
+}
 
-            let properties = properties.unwrap();
-            if let None = properties {
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pass complete: {} discovered, {} matched, {} read successfully, {} failed",
+            self.discovered, self.matched, self.read, self.failed)
+    }
+}
+
+async fn query_devices(
+    ctx: &QueryContext,
+    source: &dyn SensorSource,
+    controls: &mut PeripheralControls
+) -> Pass {
+    let scan = match source.scan().await {
+        Ok(scan) => scan,
+        Err(err) => {
+            warn!("Could not scan for devices: {:?}", err);
+            return Pass::default();
+        }
+    };
+    ctx.metrics.gauge_peripherals_discovered.set(scan.peripherals as i64);
+
+    let subscribed = if ctx.config.notifications { source.subscribed() } else { Vec::new() };
+
+    let mut pass = Pass { discovered: scan.advertisements.len(), ..Pass::default() };
+    let mut due = Vec::new();
+    // of the due devices, for `adapter_label`
+    let mut adapters = HashMap::new();
+    for Advertisement { serial, rssi, adapter } in scan.advertisements {
+        if let (Some(min_rssi), Some(rssi)) = (ctx.config.min_rssi, rssi) {
+            if rssi < min_rssi {
+                trace!("peripheral {} advertises at {} dBm, below min_rssi, skipping", serial, rssi);
                 continue;
             }
+        }
+        let peripheral_control = match control_for(ctx, controls, serial) {
+            Some(peripheral_control) => peripheral_control,
+            None => continue,
+        };
+        pass.matched += 1;
 
-            let properties = properties.unwrap();
-            let manufacturer_data = properties.manufacturer_data;
+        // advertised on every pass, so tracked even when the device is not due a query
+        if let Some(rssi) = rssi {
+            ctx.metrics.gauge_rssi.with_label_values(&[&serial.to_string()]).set(rssi as i64);
+        }
 
-            if let Some(serial) = sensor::parse_serial(manufacturer_data) {
-                let peripheral_control = controls.entry(serial).or_insert_with(||
-                    control::new_peripheral_control(
-                        Duration::from_secs(5*60),
-                        Rc::clone(metrics),
-                        devices_labels.get(&*serial.to_string()).unwrap(),
-                    )
-                );
+        if subscribed.contains(&serial) {
+            trace!("peripheral {} pushes its values, skipping", serial);
+            continue;
+        }
+        if !peripheral_control.should_query(Instant::now()) {
+            trace!("peripheral {} queried recently, skipping", serial);
+            continue;
+        }
+        due.push(serial);
+        if let (true, Some(adapter)) = (ctx.config.adapter_label, adapter) {
+            adapters.insert(serial, adapter);
+        }
+    }
+    ctx.metrics.gauge_devices_matched.set(pass.matched as i64);
 
-                if !peripheral_control.should_query(Instant::now()) {
-                    trace!("peripheral {} queried recently, skipping", serial);
-                    continue;
+    // reads overlap, controls are updated one result at a time as they come in
+    let mut results = stream::iter(due)
+        .map(|serial| async move { (serial, query_device(ctx, source, serial).await) })
+        .buffer_unordered(ctx.config.max_concurrent_queries);
+    while let Some((serial, result)) = results.next().await {
+        let serial_label = serial.to_string();
+        match result {
+            Ok(values) => {
+                if let Some(peripheral_control) = controls.get_mut(&serial) {
+                    if let Some(adapter) = adapters.get(&serial) {
+                        peripheral_control.set_adapter(adapter);
+                    }
+                    record_values(ctx, peripheral_control, serial, values);
                 }
-
-                trace!("querying peripheral {}", serial);
-                let result = query_peripheral(peripheral, peripheral_control).await;
-                if let Err(err) = result {
-                    debug!("Failed to query peripheral {}, skipped: {:?}", serial, err);
+                ctx.metrics.counter_read_success.with_label_values(&[&serial_label]).inc();
+                pass.read += 1;
+            }
+            Err(err) => {
+                pass.failed += 1;
+                ctx.metrics.counter_read_failure.with_label_values(&[&serial_label, err.reason.as_str()]).inc();
+                if err.cause.downcast_ref::<Timeout>().is_some() {
+                    ctx.metrics.counter_read_timeout.with_label_values(&[&serial_label]).inc();
                 }
 
-                // don't ever disconnect, it's a noop atm anyway
+                if let Some(parse_err) = err.cause.downcast_ref::<ParseError>() {
+                    warn!("peripheral {} sent a payload we can not parse, skipped: {}", serial, parse_err);
+                } else {
+                    warn!("Failed to query peripheral {} after {} attempts, skipped: {:?}", serial, ctx.config.retry.attempts, err);
+                }
             }
         }
     }
+    pass
 }
 
-async fn query_peripheral(peripheral: &impl Peripheral, peripheral_control: &mut Box<dyn PeripheralControl<SensorValues>>) -> Result<()> {
-    // Connect if we aren't already connected.
-    let is_connected = peripheral.is_connected().await.context("Failed to check if device is connected")?;
-    if !is_connected {
-        peripheral.connect().await.context("Failed to connect to a peripheral")?
+/// exports values a subscribed device pushed, as soon as they arrive
+fn apply_notification(
+    ctx: &QueryContext,
+    controls: &mut HashMap<u32, Box<dyn PeripheralControl<SensorValues>>>,
+    serial: u32,
+    values: SensorValues,
+) {
+    if let Some(peripheral_control) = controls.get_mut(&serial) {
+        trace!("peripheral {} pushed new values", serial);
+        ctx.metrics.counter_notifications.with_label_values(&[&serial.to_string()]).inc();
+        record_values(ctx, peripheral_control, serial, values);
     }
+}
 
-    // discover services and characteristics
-    peripheral.discover_services().await.context("Failed to discover services")?;
+/// exports values read from a device or pushed by it, leaving out implausible ones
+fn record_values(
+    ctx: &QueryContext,
+    peripheral_control: &mut Box<dyn PeripheralControl<SensorValues>>,
+    serial: u32,
+    mut values: SensorValues,
+) {
+    for field in values.remove_implausible() {
+        debug!("peripheral {} reported an implausible {}, skipped", serial, field);
+        ctx.metrics.counter_implausible.with_label_values(&[&serial.to_string(), field]).inc();
+    }
+    peripheral_control.update(Instant::now(), &values);
+}
 
-    // find the characteristic we want
-    let chars = peripheral.characteristics();
-    let char = chars
-        .iter()
-        .find(|c| c.uuid == SENSORVALUES_CHARACTERISTIC_UUID);
+/// reads a device, and its info while connected, holding a connection slot until it is disconnected
+async fn query_device(ctx: &QueryContext, source: &dyn SensorSource, serial: u32) -> Result<SensorValues, QueryError> {
+    trace!("querying peripheral {}", serial);
+    let slot = ctx.connections.acquire(source, serial).await;
+    let values = match read_with_retries(source, &ctx.config.retry, serial).await {
+        Ok(values) => values,
+        Err(err) => {
+            disconnect(source, serial).await;
+            return Err(err);
+        }
+    };
 
-    if let None = char {
-        return Err(anyhow!("Failed to find correct characteristic"));
+    match source.device_info(serial).await {
+        Ok(DeviceInfo { model, firmware }) => {
+            let model = model.unwrap_or_default();
+            let firmware = firmware.unwrap_or_default();
+            ctx.metrics.gauge_device_info.with_label_values(&[&serial.to_string(), &model, &firmware]).set(1);
+        }
+        Err(err) => debug!("Failed to read device info from peripheral {}: {:?}", serial, err),
     }
-    let char = char.unwrap();
 
-    let data = peripheral.read(char).await.context("Failed to read data from characteristic")?;
-    peripheral_control.update(Instant::now(), &SensorValues::from_vec(data));
-    Ok(())
+    if ctx.config.notifications {
+        match source.subscribe(serial).await {
+            Ok(true) => info!("peripheral {} pushes its values from now on", serial),
+            Ok(false) => debug!("peripheral {} can not push its values, polling it", serial),
+            Err(err) => debug!("Failed to subscribe to peripheral {}, polling it: {:?}", serial, err),
+        }
+    }
+
+    // bluez runs out of connection slots with many devices held connected
+    if ctx.config.disconnect_after_read {
+        disconnect(source, serial).await;
+    } else {
+        ctx.connections.keep(slot);
+    }
+    Ok(values)
 }
 
-async fn start_scanning() -> Result<Vec<Adapter>> {
-    let manager = Manager::new().await?;
-    let adapter_list = manager.adapters().await?;
-    if adapter_list.is_empty() {
-        panic!("No Bluetooth adapters found");
+async fn disconnect(source: &dyn SensorSource, serial: u32) {
+    if let Err(err) = source.disconnect(serial).await {
+        debug!("Failed to disconnect from peripheral {}: {:?}", serial, err);
     }
+}
+
+/// finds or creates the control for a serial, `None` if the device should not be queried
+fn control_for<'a>(
+    ctx: &QueryContext,
+    controls: &'a mut PeripheralControls,
+    serial: u32,
+) -> Option<&'a mut Box<dyn PeripheralControl<SensorValues>>> {
+    match controls.entry(serial) {
+        Entry::Occupied(entry) => Some(entry.into_mut()),
+        Entry::Vacant(entry) => match ctx.config.device(serial) {
+            Some(device) => {
+                let mut peripheral_control = control::new_peripheral_control(
+                    &device,
+                    &ctx.config.label_names,
+                    ctx.config.query_strategy,
+                    ctx.config.smoothing,
+                    ctx.config.deadband,
+                    Arc::clone(&ctx.metrics),
+                    Arc::clone(&ctx.sinks),
+                );
+
+                let now = Instant::now();
+                let saved_interval = ctx.saved_intervals.get(&serial)
+                    .and_then(|saved| saved.to_instants(now, SystemTime::now()));
+                if let Some(interval) = saved_interval {
+                    debug!("peripheral {} resumes from a saved interval", serial);
+                    peripheral_control.restore_expected_interval(now, interval);
+                }
+
+                Some(entry.insert(peripheral_control))
+            }
+            None => {
+                debug!("peripheral {} is not configured, skipping", serial);
+                None
+            }
+        },
+    }
+}
+
+/// retries flaky connects and reads with exponential backoff, a device answering with something we can not use,
+/// see `FailureReason::is_final`, is not retried
+async fn read_with_retries(
+    source: &dyn SensorSource,
+    retry: &RetryConfig,
+    serial: u32,
+) -> Result<SensorValues, QueryError> {
+    let mut attempt = 1;
+    loop {
+        match source.read(serial).await {
+            Ok(values) => return Ok(values),
+            Err(err) if attempt >= retry.attempts || err.reason.is_final() => return Err(err),
+            Err(err) => {
+                let delay = retry.backoff(attempt);
+                debug!("attempt {} to query peripheral {} failed, retrying in {:?}: {:?}", attempt, serial, delay, err);
+                time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+
+    use super::{query_devices, Pass, QueryContext};
+    use crate::config::{
+        Config, Deadband, DeviceConfig, MetricsConfig, QueryStrategy, Readiness, RetryConfig, ScanSchedule, Smoothing,
+        Units,
+    };
+    use crate::connections::ConnectionLimit;
+    use crate::metrics::CustomMetrics;
+    use crate::mock::MockReadings;
+    use crate::sensor::{ParseError, SensorModel, SensorValues};
+    use crate::server::Probes;
+    use crate::source::{Advertisement, DeviceInfo, FailureReason, QueryError, Scan, SensorSource, Timeout, WithReason};
+
+    const STUDY: u32 = 2930025667;
+
+    fn config(auto_discover: bool) -> Config {
+        let label_names = vec![String::from("serial"), String::from("room")];
+        let mut devices = HashMap::new();
+        devices.insert(STUDY, DeviceConfig {
+            serial: STUDY,
+            label_values: vec![STUDY.to_string(), String::from("Study")],
+            update_interval: Duration::from_secs(300),
+            stale_after: Duration::from_secs(600),
+            stale_grace: Duration::ZERO,
+            units: Units::default(),
+        });
+        Config {
+            devices, label_names, auto_discover,
+            disabled: HashSet::new(),
+            adapter_label: false,
+            missing_label_value: String::new(),
+            update_interval: Duration::from_secs(300),
+            stale_after: None,
+            stale_grace: Duration::ZERO,
+            scan_interval: Duration::from_secs(5),
+            scan_schedule: ScanSchedule::AfterPass,
+            scan_watchdog_passes: 60,
+            scan_start_attempts: 5,
+            min_rssi: None,
+            max_concurrent_queries: 4,
+            max_concurrent_connections: 4,
+            query_timeout: Duration::from_secs(30),
+            company_ids: vec![820],
+            query_strategy: QueryStrategy::Adaptive,
+            readiness: Readiness::Any,
+            retry: RetryConfig { attempts: 3, base_delay_ms: 0 },
+            disconnect_after_read: false,
+            notifications: false,
+            tls: None,
+            metrics: MetricsConfig::default(),
+            units: Units::default(),
+            smoothing: Smoothing::default(),
+            deadband: Deadband::default(),
+            mqtt: None,
+            influxdb: None,
+            pushgateway: None,
+            sqlite: None,
+            csv: None,
+            textfile_path: None,
+            textfile_timestamps: true,
+        }
+    }
+
+    fn context(config: Config) -> QueryContext {
+        let (metrics, _) = CustomMetrics::new(&config.label_names, &config.metrics).unwrap();
+        QueryContext {
+            connections: ConnectionLimit::new(config.max_concurrent_connections),
+            config,
+            metrics: Arc::new(metrics),
+            sinks: Arc::new(Vec::new()),
+            saved_intervals: HashMap::new(),
+        }
+    }
+
+    /// Advertises the given serials and answers reads from a script, then with the fixed mock reading.
+    struct ScriptedSource {
+        serials: Vec<u32>,
+        script: RefCell<VecDeque<Result<SensorValues, QueryError>>>,
+        reads: RefCell<Vec<u32>>,
+        in_flight: Cell<usize>,
+        max_in_flight: Cell<usize>,
+        connected: RefCell<HashSet<u32>>,
+        max_connected: Cell<usize>,
+        scan_restarts: Cell<usize>,
+        /// scan restarts fail
+        scan_broken: Cell<bool>,
+        subscriptions: RefCell<Vec<u32>>,
+    }
+
+    impl ScriptedSource {
+        fn new(serials: Vec<u32>, script: Vec<Result<SensorValues, QueryError>>) -> ScriptedSource {
+            ScriptedSource {
+                serials,
+                script: RefCell::new(script.into()),
+                reads: RefCell::new(Vec::new()),
+                in_flight: Cell::new(0),
+                max_in_flight: Cell::new(0),
+                connected: RefCell::new(HashSet::new()),
+                max_connected: Cell::new(0),
+                scan_restarts: Cell::new(0),
+                scan_broken: Cell::new(false),
+                subscriptions: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl SensorSource for ScriptedSource {
+        async fn scan(&self) -> Result<Scan> {
+            Ok(Scan {
+                advertisements: self.serials.iter()
+                    .map(|&serial| Advertisement { serial, rssi: Some(-70), adapter: Some(String::from("hci0")) })
+                    .collect(),
+                // and a neighbour's headphones
+                peripherals: self.serials.len() + 1,
+            })
+        }
+
+        fn adapters(&self) -> usize {
+            1
+        }
+
+        async fn read(&self, serial: u32) -> Result<SensorValues, QueryError> {
+            self.reads.borrow_mut().push(serial);
+            self.connected.borrow_mut().insert(serial);
+            self.max_connected.set(self.max_connected.get().max(self.connected.borrow().len()));
+            self.in_flight.set(self.in_flight.get() + 1);
+            self.max_in_flight.set(self.max_in_flight.get().max(self.in_flight.get()));
+            // lets other reads start, like waiting on the radio would
+            tokio::task::yield_now().await;
+            self.in_flight.set(self.in_flight.get() - 1);
+
+            let scripted = self.script.borrow_mut().pop_front();
+            match scripted {
+                Some(result) => result,
+                None => MockReadings::fixed(vec![serial]).read(serial).await,
+            }
+        }
+
+        async fn read_raw(&self, serial: u32) -> Result<(SensorModel, Vec<u8>)> {
+            Err(anyhow!("no raw payload for {}", serial))
+        }
+
+        async fn device_info(&self, _serial: u32) -> Result<DeviceInfo> {
+            Ok(DeviceInfo { model: Some(String::from("2930")), firmware: None })
+        }
+
+        async fn disconnect(&self, serial: u32) -> Result<()> {
+            self.connected.borrow_mut().remove(&serial);
+            Ok(())
+        }
+
+        async fn subscribe(&self, serial: u32) -> Result<bool> {
+            self.subscriptions.borrow_mut().push(serial);
+            Ok(true)
+        }
+
+        fn subscribed(&self) -> Vec<u32> {
+            self.subscriptions.borrow().clone()
+        }
+
+        async fn next_notification(&self) -> (u32, SensorValues) {
+            std::future::pending().await
+        }
+
+        async fn restart_scan(&self) -> Result<()> {
+            self.scan_restarts.set(self.scan_restarts.get() + 1);
+            if self.scan_broken.get() {
+                return Err(anyhow!("adapter gone"));
+            }
+            Ok(())
+        }
+
+        async fn disconnect_all(&self) {}
+    }
+
+    fn reads(ctx: &QueryContext, serial: u32) -> u64 {
+        ctx.metrics.counter_read_success.with_label_values(&[&serial.to_string()]).get()
+    }
+
+    fn failures(ctx: &QueryContext, serial: u32, reason: &str) -> u64 {
+        ctx.metrics.counter_read_failure.with_label_values(&[&serial.to_string(), reason]).get()
+    }
+
+    #[test]
+    fn raw_payload_is_dumped_as_hex() {
+        let line = super::raw_line(STUDY, SensorModel::WaveMini, &[1, 0xa0, 0xff]);
+        assert_eq!(line, "2930025667 WaveMini 01 a0 ff");
+    }
+
+    #[test]
+    fn config_summary_lists_devices_and_labels() {
+        let summary = super::config_summary("devices.toml", &config(true));
+        assert_eq!(summary, "devices.toml is valid\n\
+            label names: serial, room\n\
+            1 devices:\n  \
+            2930025667 serial=\"2930025667\" room=\"Study\", update interval 300s, stale after 600s\n\
+            devices missing from the config are discovered too\n");
+    }
+
+    #[test]
+    fn unconfigured_serial_is_skipped() {
+        let ctx = context(config(false));
+        let mut controls = HashMap::new();
+
+        assert!(super::control_for(&ctx, &mut controls, 1234).is_none());
+        assert!(super::control_for(&ctx, &mut controls, STUDY).is_some());
+        assert_eq!(controls.len(), 1);
+    }
+
+    #[test]
+    fn unconfigured_serial_is_registered_when_auto_discovering() {
+        let ctx = context(config(true));
+        let mut controls = HashMap::new();
+
+        assert!(super::control_for(&ctx, &mut controls, 1234).is_some());
+        assert!(controls.contains_key(&1234));
+    }
+
+    #[tokio::test]
+    async fn only_configured_devices_are_read_once_per_interval() {
+        let ctx = context(config(false));
+        let mut controls = HashMap::new();
+        let source = ScriptedSource::new(vec![STUDY, 1234], vec![]);
+
+        query_devices(&ctx, &source, &mut controls).await;
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(*source.reads.borrow(), vec![STUDY], "queried again only once the update interval passed");
+        assert_eq!(reads(&ctx, STUDY), 1);
+        assert_eq!(ctx.metrics.gauge_co2.as_ref().unwrap().with_label_values(&[&STUDY.to_string(), "Study"]).get(), 600);
+        assert_eq!(ctx.metrics.gauge_rssi.with_label_values(&[&STUDY.to_string()]).get(), -70);
+        assert_eq!(ctx.metrics.gauge_expected_interval.with_label_values(&[&STUDY.to_string()]).get(), 300.0);
+        assert_eq!(ctx.metrics.gauge_device_info.with_label_values(&[&STUDY.to_string(), "2930", ""]).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_overlap_up_to_the_limit() {
+        let mut config = config(true);
+        config.max_concurrent_queries = 3;
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+        let source = ScriptedSource::new((1..=5).collect(), vec![]);
 
-    for adapter in adapter_list.iter() {
-        info!("Starting scan...");
-        adapter
-            .start_scan(ScanFilter { services: vec!(SENSORVALUES_SERVICE_UUID) })
-            .await
-            .expect("Can't scan BLE adapter for connected devices...");
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(source.max_in_flight.get(), 3);
+        assert!((1..=5).all(|serial| reads(&ctx, serial) == 1));
+    }
+
+    #[tokio::test]
+    async fn connections_are_limited_below_the_queries() {
+        let mut config = config(true);
+        config.max_concurrent_queries = 4;
+        config.max_concurrent_connections = 2;
+        config.disconnect_after_read = true;
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+        let source = ScriptedSource::new((1..=5).collect(), vec![]);
+
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(source.max_in_flight.get(), 2);
+        assert_eq!(source.max_connected.get(), 2);
+        assert!((1..=5).all(|serial| reads(&ctx, serial) == 1));
+        assert!(source.connected.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn devices_left_connected_hold_their_connection() {
+        let mut config = config(true);
+        config.max_concurrent_queries = 4;
+        config.max_concurrent_connections = 2;
+        config.disconnect_after_read = false;
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+        let source = ScriptedSource::new((1..=5).collect(), vec![]);
+
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(source.max_connected.get(), 2, "connected devices are disconnected for the next ones");
+        assert!((1..=5).all(|serial| reads(&ctx, serial) == 1));
+        assert_eq!(source.connected.borrow().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn configured_devices_not_seen_are_unmatched() {
+        let mut config = config(false);
+        config.devices.insert(1, DeviceConfig { serial: 1, ..config.devices[&STUDY].clone() });
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+
+        query_devices(&ctx, &ScriptedSource::new(vec![STUDY], vec![]), &mut controls).await;
+
+        assert_eq!(super::unmatched_serials(&ctx.config.devices, &controls), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn devices_below_min_rssi_are_not_read() {
+        let mut config = config(true);
+        config.min_rssi = Some(-60);
+        let mut ctx = context(config);
+        let mut controls = HashMap::new();
+        let source = ScriptedSource::new(vec![STUDY], vec![]);
+
+        // advertised at -70 dBm
+        query_devices(&ctx, &source, &mut controls).await;
+        assert!(source.reads.borrow().is_empty());
+        assert!(controls.is_empty(), "not discovered either");
+
+        ctx.config.min_rssi = Some(-80);
+        query_devices(&ctx, &source, &mut controls).await;
+        assert_eq!(*source.reads.borrow(), vec![STUDY]);
+    }
+
+    #[tokio::test]
+    async fn scan_counts_are_exported() {
+        let ctx = context(config(false));
+        let mut controls = HashMap::new();
+
+        let pass = query_devices(&ctx, &ScriptedSource::new(vec![STUDY, 1234], vec![]), &mut controls).await;
+
+        assert_eq!(ctx.metrics.gauge_peripherals_discovered.get(), 3);
+        assert_eq!(ctx.metrics.gauge_devices_matched.get(), 1, "only configured devices are matched");
+        assert_eq!(pass, Pass { discovered: 2, matched: 1, read: 1, failed: 0 });
+        assert_eq!(pass.to_string(), "pass complete: 2 discovered, 1 matched, 1 read successfully, 0 failed");
+    }
+
+    #[tokio::test]
+    async fn scan_is_restarted_when_nothing_is_discovered() {
+        let mut config = config(false);
+        config.scan_watchdog_passes = 3;
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+        let mut watchdog = super::ScanWatchdog::default();
+        let probes = Probes::new(Readiness::Any, vec![]);
+        let silent = ScriptedSource::new(vec![], vec![]);
+
+        for _ in 0..2 {
+            let pass = query_devices(&ctx, &silent, &mut controls).await;
+            watchdog.observe(&ctx, &silent, &probes, pass.discovered).await;
+        }
+        let pass = query_devices(&ctx, &ScriptedSource::new(vec![STUDY], vec![]), &mut controls).await;
+        watchdog.observe(&ctx, &silent, &probes, pass.discovered).await;
+        assert_eq!(silent.scan_restarts.get(), 0, "a discovery resets the count");
+
+        for _ in 0..3 {
+            let pass = query_devices(&ctx, &silent, &mut controls).await;
+            watchdog.observe(&ctx, &silent, &probes, pass.discovered).await;
+        }
+        assert_eq!(silent.scan_restarts.get(), 1);
+        assert_eq!(ctx.metrics.counter_adapter_resets.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn not_live_while_the_scan_can_not_be_restarted() {
+        let mut config = config(false);
+        config.scan_watchdog_passes = 2;
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+        let mut watchdog = super::ScanWatchdog::default();
+        let probes = Probes::new(Readiness::Any, vec![]);
+        probes.set_scanning(true);
+        let silent = ScriptedSource::new(vec![], vec![]);
+        silent.scan_broken.set(true);
+
+        for _ in 0..2 {
+            let pass = query_devices(&ctx, &silent, &mut controls).await;
+            watchdog.observe(&ctx, &silent, &probes, pass.discovered).await;
+        }
+        assert!(!probes.is_live());
+
+        silent.scan_broken.set(false);
+        for _ in 0..2 {
+            let pass = query_devices(&ctx, &silent, &mut controls).await;
+            watchdog.observe(&ctx, &silent, &probes, pass.discovered).await;
+        }
+        assert!(probes.is_live(), "live again once the scan restarted");
+
+        probes.set_scanning(false);
+        let pass = query_devices(&ctx, &ScriptedSource::new(vec![STUDY], vec![]), &mut controls).await;
+        watchdog.observe(&ctx, &silent, &probes, pass.discovered).await;
+        assert!(probes.is_live(), "and once devices are discovered");
+    }
+
+    #[tokio::test]
+    async fn disabled_metrics_are_not_created() {
+        let mut config = config(false);
+        config.metrics.enabled = Some(vec![String::from("radon_short"), String::from("co2")]);
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+
+        query_devices(&ctx, &ScriptedSource::new(vec![STUDY], vec![]), &mut controls).await;
+
+        assert!(ctx.metrics.gauge_co2.is_some());
+        assert!(ctx.metrics.gauge_voc.is_none());
+        assert!(ctx.metrics.gauge_temp.is_none());
+        assert_eq!(reads(&ctx, STUDY), 1);
     }
 
-    Ok(adapter_list)
+    #[tokio::test]
+    async fn flaky_reads_are_retried() {
+        let ctx = context(config(false));
+        let mut controls = HashMap::new();
+        let flaky = || Err(anyhow!("le-connection-abort-by-local")).reason(FailureReason::Connect);
+        let source = ScriptedSource::new(vec![STUDY], vec![flaky(), flaky()]);
+
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(source.reads.borrow().len(), 3);
+        assert_eq!(reads(&ctx, STUDY), 1);
+        assert_eq!(failures(&ctx, STUDY, "connect"), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_reads_are_counted_and_skipped() {
+        let ctx = context(config(false));
+        let mut controls = HashMap::new();
+        let unparseable = ParseError::TooShort { model: SensorModel::WavePlus, expected: 16, actual: 3 };
+        let source = ScriptedSource::new(vec![STUDY], vec![Err(unparseable).reason(FailureReason::Parse)]);
+
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(source.reads.borrow().len(), 1, "a payload we can not parse is not retried");
+        assert_eq!(failures(&ctx, STUDY, "parse"), 1);
+        assert_eq!(reads(&ctx, STUDY), 0);
+        assert!(controls[&STUDY].should_query(Instant::now()), "still due a query");
+    }
+
+    #[tokio::test]
+    async fn missing_characteristic_is_not_retried() {
+        let ctx = context(config(false));
+        let mut controls = HashMap::new();
+        let unsupported = Err(anyhow!("no sensor values characteristic")).reason(FailureReason::Characteristic);
+        let source = ScriptedSource::new(vec![STUDY], vec![unsupported]);
+
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(source.reads.borrow().len(), 1);
+        assert_eq!(failures(&ctx, STUDY, "characteristic"), 1);
+    }
+
+    #[tokio::test]
+    async fn timed_out_reads_are_counted() {
+        let mut config = config(false);
+        config.retry.attempts = 1;
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+        let timed_out = Err(Timeout(Duration::from_secs(30))).reason(FailureReason::Connect);
+        let source = ScriptedSource::new(vec![STUDY], vec![timed_out]);
+
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(failures(&ctx, STUDY, "connect"), 1);
+        assert_eq!(ctx.metrics.counter_read_timeout.with_label_values(&[&STUDY.to_string()]).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_devices_are_removed_from_metrics() {
+        let ctx = context(config(false));
+        let mut controls = HashMap::new();
+        let labels = [STUDY.to_string(), String::from("Study")];
+        let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+
+        query_devices(&ctx, &ScriptedSource::new(vec![STUDY], vec![]), &mut controls).await;
+        controls.get_mut(&STUDY).unwrap().remove_metric_if_stale(Instant::now() + Duration::from_secs(601));
+
+        assert!(ctx.metrics.gauge_co2.as_ref().unwrap().remove_label_values(&labels).is_err(), "already removed");
+        assert!(ctx.metrics.gauge_last_seen.remove_label_values(&labels).is_ok(), "last seen is kept");
+    }
+
+    #[tokio::test]
+    async fn subscribed_devices_are_updated_from_pushed_values() {
+        let mut config = config(false);
+        config.notifications = true;
+        let ctx = context(config);
+        let mut controls = HashMap::new();
+        let source = ScriptedSource::new(vec![STUDY], vec![]);
+
+        query_devices(&ctx, &source, &mut controls).await;
+        assert_eq!(*source.subscriptions.borrow(), vec![STUDY], "subscribed after the first read");
+
+        let mut pushed = MockReadings::fixed(vec![STUDY]).read(STUDY).await.unwrap();
+        pushed.co2 = Some(800);
+        super::apply_notification(&ctx, &mut controls, STUDY, pushed);
+        query_devices(&ctx, &source, &mut controls).await;
+
+        assert_eq!(*source.reads.borrow(), vec![STUDY], "not read again while subscribed");
+        let co2 = ctx.metrics.gauge_co2.as_ref().unwrap();
+        assert_eq!(co2.with_label_values(&[&STUDY.to_string(), "Study"]).get(), 800);
+        assert_eq!(ctx.metrics.counter_notifications.with_label_values(&[&STUDY.to_string()]).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn reloaded_config_relabels_and_drops_devices() {
+        let mut ctx = context(config(false));
+        let mut controls = HashMap::new();
+        let source = ScriptedSource::new(vec![STUDY], vec![]);
+        query_devices(&ctx, &source, &mut controls).await;
+
+        let mut relabelled = config(false);
+        relabelled.devices.get_mut(&STUDY).unwrap().label_values[1] = String::from("Office");
+        super::apply_config(&mut ctx, &mut controls, relabelled).unwrap();
+
+        let co2 = ctx.metrics.gauge_co2.as_ref().unwrap();
+        assert!(co2.remove_label_values(&[&STUDY.to_string(), "Study"]).is_err(), "old labels removed");
+        assert!(!controls[&STUDY].should_query(Instant::now()), "learned interval kept");
+
+        let mut removed = config(false);
+        removed.devices.clear();
+        super::apply_config(&mut ctx, &mut controls, removed).unwrap();
+
+        assert!(controls.is_empty());
+        assert!(ctx.metrics.gauge_device_configured.remove_label_values(&[&STUDY.to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn disabled_device_keeps_its_learned_interval() {
+        let mut ctx = context(config(false));
+        let mut controls = HashMap::new();
+        query_devices(&ctx, &ScriptedSource::new(vec![STUDY], vec![]), &mut controls).await;
+
+        let mut disabled = config(false);
+        disabled.devices.remove(&STUDY);
+        disabled.disabled.insert(STUDY);
+        super::apply_config(&mut ctx, &mut controls, disabled).unwrap();
+        query_devices(&ctx, &ScriptedSource::new(vec![STUDY], vec![]), &mut controls).await;
+
+        assert!(controls.is_empty());
+        assert_eq!(reads(&ctx, STUDY), 1, "not read while disabled");
+
+        super::apply_config(&mut ctx, &mut controls, config(false)).unwrap();
+        let control = super::control_for(&ctx, &mut controls, STUDY).unwrap();
+        assert!(!control.should_query(Instant::now()), "learned interval kept");
+    }
+
+    #[test]
+    fn reloaded_label_names_are_rejected() {
+        let mut ctx = context(config(false));
+        let mut controls = HashMap::new();
+        let mut config = config(false);
+        config.label_names.push(String::from("floor"));
+
+        assert!(super::apply_config(&mut ctx, &mut controls, config).is_err());
+        assert_eq!(ctx.config.label_names, vec!["serial", "room"]);
+    }
 }
@@ -1,67 +1,309 @@
-use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
+use anyhow::Context;
+use prometheus::{
+    Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
 use std::sync::Arc;
 use tokio::sync::Notify;
-use prometheus_hyper::{RegistryFn, Server};
+use tokio::task::JoinHandle;
+use prometheus_hyper::RegistryFn;
 use std::net::SocketAddr;
 use prometheus::core::Collector;
 
-pub fn create_metrics(label_names: &Vec<String>) -> CustomMetrics {
+use crate::config::{Config, MetricsConfig};
+use crate::readings::Readings;
+use crate::server;
+use crate::server::Probes;
+use crate::tls;
+
+/// registers the metrics and serves them on `addr` until `shutdown` is notified, the returned task finishes
+/// early only if the server fails. Fails if tls is configured but can't be loaded
+pub fn create_metrics(
+    config: &Config,
+    addr: SocketAddr,
+    readings: Readings,
+    probes: Probes,
+    shutdown: Arc<Notify>,
+) -> anyhow::Result<(CustomMetrics, JoinHandle<anyhow::Result<()>>)> {
+    let tls = config.tls.as_ref().map(tls::load).transpose()?;
+    let auth = config.metrics.auth.clone();
     let registry = Arc::new(Registry::new());
-    let shutdown = Arc::new(Notify::new());
-    let shutdown_clone = Arc::clone(&shutdown);
-    let (metrics, f) = CustomMetrics::new(label_names)
-        .expect("failed creating metrics");
-    f(&registry).expect("failed registering metrics");
+    let (metrics, f) = CustomMetrics::new(&config.label_names, &config.metrics).context("failed creating metrics")?;
+    f(&registry).context("failed registering metrics")?;
+
+    if let Some(pushgateway_config) = &config.pushgateway {
+        #[cfg(feature = "pushgateway")]
+        crate::pushgateway::spawn_pusher(pushgateway_config.clone(), Arc::clone(&registry));
+        #[cfg(not(feature = "pushgateway"))]
+        log::warn!("[pushgateway] is configured for {}, but this build lacks the pushgateway feature", pushgateway_config.url);
+    }
 
     // Startup Server
-    let _jh = tokio::spawn(async move {
-        Server::run(
+    let server = tokio::spawn(async move {
+        server::run(
             Arc::clone(&registry),
-            SocketAddr::from(([0; 4], 8080)),
-            shutdown_clone.notified(),
+            readings,
+            probes,
+            addr,
+            tls,
+            auth,
+            shutdown.notified(),
         ).await
     });
-    metrics
+    Ok((metrics, server))
 }
 
+/// Keys of the sensor gauges, as turned off with `[metrics] enabled`, the exporter's own metrics always stay.
+/// They are also the names the gauges are exported as with `legacy_names`.
+pub const SENSOR_METRICS: [&str; 16] = [
+    "humidity", "humidity_ratio", "temperature", "temperature_fahrenheit", "atm_pressure", "dew_point_celsius", "absolute_humidity",
+    "radon_short", "radon_long", "radon_short_pcil", "radon_long_pcil",
+    "co2", "voc", "battery", "co2_level", "voc_level",
+];
+
+/// Sensor gauges are `None` when disabled.
 pub struct CustomMetrics {
-    pub gauge_humidity: GaugeVec,
-    pub gauge_temp: GaugeVec,
-    pub gauge_atm: GaugeVec,
-    pub gauge_radon_short: IntGaugeVec,
-    pub gauge_radon_long: IntGaugeVec,
-    pub gauge_co2: IntGaugeVec,
-    pub gauge_voc: IntGaugeVec,
+    pub gauge_humidity: Option<GaugeVec>,
+    pub gauge_humidity_ratio: Option<GaugeVec>,
+    pub gauge_temp: Option<GaugeVec>,
+    pub gauge_temp_fahrenheit: Option<GaugeVec>,
+    pub gauge_atm: Option<GaugeVec>,
+    /// derived from temperature and humidity
+    pub gauge_dew_point: Option<GaugeVec>,
+    /// derived from temperature and humidity
+    pub gauge_absolute_humidity: Option<GaugeVec>,
+    pub gauge_radon_short: Option<IntGaugeVec>,
+    pub gauge_radon_long: Option<IntGaugeVec>,
+    pub gauge_radon_short_pcil: Option<GaugeVec>,
+    pub gauge_radon_long_pcil: Option<GaugeVec>,
+    pub gauge_co2: Option<IntGaugeVec>,
+    pub gauge_voc: Option<IntGaugeVec>,
+    pub gauge_battery: Option<IntGaugeVec>,
+    /// 0 good, 1 fair, 2 poor, by Airthings' thresholds
+    pub gauge_co2_level: Option<IntGaugeVec>,
+    pub gauge_voc_level: Option<IntGaugeVec>,
+    /// kept when a device goes stale, so staleness can be alerted on
+    pub gauge_last_seen: GaugeVec,
+    /// when a read first returned the current values, the device measured them since the read before
+    pub gauge_last_change: GaugeVec,
+    /// 1 while a device's values are stale but kept for the grace period, 0 while fresh
+    pub gauge_stale: IntGaugeVec,
+    /// signal strength as last seen in discovery, labelled by serial
+    pub gauge_rssi: IntGaugeVec,
+    /// width of the window the next value change is expected in, narrows as polling locks on
+    pub gauge_expected_interval: GaugeVec,
+    /// always 1, for every serial in the config, missing devices are those without `gauge_last_seen`
+    pub gauge_device_configured: IntGaugeVec,
+    /// always 1, labelled by serial, model and firmware
+    pub gauge_device_info: IntGaugeVec,
+    pub counter_read_success: IntCounterVec,
+    /// labelled by serial and the query step that failed
+    pub counter_read_failure: IntCounterVec,
+    /// values dropped as out of range, labelled by serial and field
+    pub counter_implausible: IntCounterVec,
+    /// failed reads the device stopped answering in, by serial
+    pub counter_read_timeout: IntCounterVec,
+    /// values pushed by subscribed devices, by serial
+    pub counter_notifications: IntCounterVec,
+    /// scans restarted after the adapter stopped reporting devices
+    pub counter_adapter_resets: IntCounter,
+    /// config reloads on SIGHUP, failed ones included
+    pub counter_config_reloads: IntCounter,
+    /// reloads that kept the running config as the new one could not be loaded or applied
+    pub counter_config_reload_errors: IntCounter,
+    /// unix time the running config was loaded at, on startup or by the latest successful reload
+    pub gauge_config_last_reload: Gauge,
+    /// how long discovery and querying devices took per pass, the pause between passes excluded
+    pub histogram_scan_cycle: Histogram,
+    /// Bluetooth adapters scanned on
+    pub gauge_adapters: IntGauge,
+    /// peripherals in range as of the latest scan, Airthings or not
+    pub gauge_peripherals_discovered: IntGauge,
+    /// devices in the latest scan that are configured, or auto discovered
+    pub gauge_devices_matched: IntGauge,
+    /// always 1, labelled by the exporter's version, the rustc it was built with and its git commit
+    pub gauge_build_info: IntGaugeVec,
 }
 
 impl CustomMetrics {
-    pub fn new(label_names: &Vec<String>) -> anyhow::Result<(Self, RegistryFn)> {
+    /// sensor gauges missing from `config.enabled` are not created, all are without it
+    pub fn new(label_names: &[String], config: &MetricsConfig) -> anyhow::Result<(Self, RegistryFn)> {
         let mut slice: Vec<&str> = Vec::new();
         for s in label_names {
-            slice.push(&s);
+            slice.push(s);
         }
         let slice = slice.as_slice();
+        let namespace = config.namespace.as_str();
+        let sensor_namespace = if config.legacy_names { "" } else { namespace };
+        let is_enabled = |key: &str| config.enabled.as_ref()
+            .is_none_or(|enabled| enabled.iter().any(|enabled| enabled == key));
+        // the legacy names are the keys
+        let opts = |key: &str, name: &str, help: &str| {
+            Opts::new(if config.legacy_names { key } else { name }, help).namespace(sensor_namespace)
+        };
+        let gauge = |key: &str, name: &str, help: &str| -> prometheus::Result<Option<GaugeVec>> {
+            is_enabled(key).then(|| GaugeVec::new(opts(key, name, help), slice)).transpose()
+        };
+        let int_gauge = |key: &str, name: &str, help: &str| -> prometheus::Result<Option<IntGaugeVec>> {
+            is_enabled(key).then(|| IntGaugeVec::new(opts(key, name, help), slice)).transpose()
+        };
 
         let metrics = Self {
-            gauge_humidity: GaugeVec::new(Opts::new("humidity", "in rel%"), slice)?,
-            gauge_temp: GaugeVec::new(Opts::new("temperature", "air temperature, in C"), slice)?,
-            gauge_atm: GaugeVec::new(Opts::new("atm_pressure", "atmospheric pressure, in mbar"), slice)?,
-            gauge_radon_short: IntGaugeVec::new(Opts::new("radon_short", "in Bq/m3"), slice)?,
-            gauge_radon_long: IntGaugeVec::new(Opts::new("radon_long", "in Bq/m3"), slice)?,
-            gauge_voc: IntGaugeVec::new(Opts::new("voc", "in ppb"), slice)?,
-            gauge_co2: IntGaugeVec::new(Opts::new("co2", "in ppm"), slice)?,
+            gauge_humidity: gauge("humidity", "relative_humidity_percent", "in rel%")?,
+            gauge_humidity_ratio: gauge("humidity_ratio", "relative_humidity_ratio", "relative humidity, 0 to 1")?,
+            gauge_temp: gauge("temperature", "temperature_celsius", "air temperature, in C")?,
+            gauge_temp_fahrenheit: gauge("temperature_fahrenheit", "temperature_fahrenheit", "air temperature, in F")?,
+            gauge_atm: gauge("atm_pressure", "atmospheric_pressure_mbar", "atmospheric pressure, in mbar")?,
+            gauge_dew_point: gauge("dew_point_celsius", "dew_point_celsius", "dew point, in C")?,
+            gauge_absolute_humidity: gauge("absolute_humidity", "absolute_humidity_grams_per_m3", "in g/m3")?,
+            gauge_radon_short: int_gauge("radon_short", "radon_short_bq_per_m3", "24 hour average, in Bq/m3")?,
+            gauge_radon_long: int_gauge("radon_long", "radon_long_bq_per_m3", "average since measuring started, in Bq/m3")?,
+            gauge_radon_short_pcil: gauge("radon_short_pcil", "radon_short_pci_per_l", "24 hour average, in pCi/L")?,
+            gauge_radon_long_pcil: gauge("radon_long_pcil", "radon_long_pci_per_l", "average since measuring started, in pCi/L")?,
+            gauge_voc: int_gauge("voc", "voc_ppb", "in ppb")?,
+            gauge_co2: int_gauge("co2", "co2_ppm", "in ppm")?,
+            gauge_battery: int_gauge("battery", "battery_percent", "battery level, in %")?,
+            gauge_co2_level: int_gauge("co2_level", "co2_level", "0 good, 1 fair, 2 poor")?,
+            gauge_voc_level: int_gauge("voc_level", "voc_level", "0 good, 1 fair, 2 poor")?,
+            gauge_last_seen: GaugeVec::new(
+                Opts::new("last_seen_timestamp_seconds", "unix time of the last successful read").namespace(namespace),
+                slice,
+            )?,
+            gauge_last_change: GaugeVec::new(
+                Opts::new("last_change_timestamp_seconds", "unix time of the first read of the current values")
+                    .namespace(namespace),
+                slice,
+            )?,
+            gauge_stale: IntGaugeVec::new(
+                Opts::new("stale", "1 while the values are stale, until they are dropped").namespace(namespace),
+                slice,
+            )?,
+            gauge_rssi: IntGaugeVec::new(
+                Opts::new("rssi_dbm", "received signal strength, in dBm").namespace(namespace),
+                &["serial"],
+            )?,
+            gauge_expected_interval: GaugeVec::new(
+                Opts::new("expected_interval_seconds", "width of the learned update window").namespace(namespace),
+                &["serial"],
+            )?,
+            gauge_device_configured: IntGaugeVec::new(
+                Opts::new("device_configured", "devices listed in the config").namespace(namespace),
+                &["serial"],
+            )?,
+            gauge_device_info: IntGaugeVec::new(
+                Opts::new("device_info", "model and firmware as reported by the device").namespace(namespace),
+                &["serial", "model", "firmware"],
+            )?,
+            counter_read_success: IntCounterVec::new(
+                Opts::new("read_success_total", "successful device reads").namespace(namespace),
+                &["serial"],
+            )?,
+            counter_read_failure: IntCounterVec::new(
+                Opts::new("read_failure_total", "failed device reads, by failed step").namespace(namespace),
+                &["serial", "reason"],
+            )?,
+            counter_implausible: IntCounterVec::new(
+                Opts::new("implausible_readings_total", "values dropped as out of plausible range").namespace(namespace),
+                &["serial", "field"],
+            )?,
+            counter_read_timeout: IntCounterVec::new(
+                Opts::new("read_timeout_total", "failed device reads that timed out").namespace(namespace),
+                &["serial"],
+            )?,
+            counter_notifications: IntCounterVec::new(
+                Opts::new("notifications_total", "values pushed by devices kept connected").namespace(namespace),
+                &["serial"],
+            )?,
+            counter_adapter_resets: IntCounter::with_opts(
+                Opts::new("adapter_resets_total", "scans restarted as no device was discovered").namespace(namespace),
+            )?,
+            counter_config_reloads: IntCounter::with_opts(
+                Opts::new("config_reload_total", "config reloads requested").namespace(namespace),
+            )?,
+            counter_config_reload_errors: IntCounter::with_opts(
+                Opts::new("config_reload_errors_total", "config reloads that kept the running config").namespace(namespace),
+            )?,
+            gauge_config_last_reload: Gauge::with_opts(
+                Opts::new("config_last_reload_timestamp_seconds", "unix time the running config was loaded")
+                    .namespace(namespace),
+            )?,
+            histogram_scan_cycle: Histogram::with_opts(
+                HistogramOpts::new("scan_cycle_duration_seconds", "time taken by a discovery and query pass")
+                    .namespace(namespace)
+                    .buckets(vec![0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]),
+            )?,
+            gauge_adapters: IntGauge::with_opts(
+                Opts::new("adapters", "bluetooth adapters scanned on").namespace(namespace),
+            )?,
+            gauge_peripherals_discovered: IntGauge::with_opts(
+                Opts::new("peripherals_discovered", "peripherals in range in the latest scan").namespace(namespace),
+            )?,
+            gauge_devices_matched: IntGauge::with_opts(
+                Opts::new("devices_matched", "devices in the latest scan that are queried").namespace(namespace),
+            )?,
+            gauge_build_info: IntGaugeVec::new(
+                Opts::new("build_info", "version the exporter was built from").namespace(namespace),
+                &["version", "rustc", "git_commit"],
+            )?,
         };
+        metrics.gauge_build_info
+            .with_label_values(&[
+                env!("CARGO_PKG_VERSION"),
+                env!("AIRTHING_RUSTC_VERSION"),
+                env!("AIRTHING_GIT_COMMIT"),
+            ])
+            .set(1);
 
-        let to_register: Vec<Box<dyn Collector>> = vec!(
-            Box::new(metrics.gauge_humidity.clone()),
-            Box::new(metrics.gauge_temp.clone()),
-            Box::new(metrics.gauge_atm.clone()),
-            Box::new(metrics.gauge_radon_short.clone()),
-            Box::new(metrics.gauge_radon_long.clone()),
-            Box::new(metrics.gauge_voc.clone()),
-            Box::new(metrics.gauge_co2.clone()),
+        let mut to_register: Vec<Box<dyn Collector>> = vec!(
+            Box::new(metrics.gauge_last_seen.clone()),
+            Box::new(metrics.gauge_last_change.clone()),
+            Box::new(metrics.gauge_stale.clone()),
+            Box::new(metrics.gauge_rssi.clone()),
+            Box::new(metrics.gauge_expected_interval.clone()),
+            Box::new(metrics.gauge_device_configured.clone()),
+            Box::new(metrics.gauge_device_info.clone()),
+            Box::new(metrics.counter_read_success.clone()),
+            Box::new(metrics.counter_read_failure.clone()),
+            Box::new(metrics.counter_implausible.clone()),
+            Box::new(metrics.counter_read_timeout.clone()),
+            Box::new(metrics.counter_notifications.clone()),
+            Box::new(metrics.counter_adapter_resets.clone()),
+            Box::new(metrics.counter_config_reloads.clone()),
+            Box::new(metrics.counter_config_reload_errors.clone()),
+            Box::new(metrics.gauge_config_last_reload.clone()),
+            Box::new(metrics.histogram_scan_cycle.clone()),
+            Box::new(metrics.gauge_build_info.clone()),
+            Box::new(metrics.gauge_adapters.clone()),
+            Box::new(metrics.gauge_peripherals_discovered.clone()),
+            Box::new(metrics.gauge_devices_matched.clone()),
         );
 
+        let sensor_gauges = [
+            &metrics.gauge_humidity,
+            &metrics.gauge_humidity_ratio,
+            &metrics.gauge_temp,
+            &metrics.gauge_temp_fahrenheit,
+            &metrics.gauge_atm,
+            &metrics.gauge_dew_point,
+            &metrics.gauge_absolute_humidity,
+            &metrics.gauge_radon_short_pcil,
+            &metrics.gauge_radon_long_pcil,
+        ];
+        let sensor_int_gauges = [
+            &metrics.gauge_radon_short,
+            &metrics.gauge_radon_long,
+            &metrics.gauge_voc,
+            &metrics.gauge_co2,
+            &metrics.gauge_battery,
+            &metrics.gauge_co2_level,
+            &metrics.gauge_voc_level,
+        ];
+        for gauge in sensor_gauges.iter().copied().flatten() {
+            to_register.push(Box::new(gauge.clone()));
+        }
+        for gauge in sensor_int_gauges.iter().copied().flatten() {
+            to_register.push(Box::new(gauge.clone()));
+        }
+
         let f = |r: &Registry| {
             for m in to_register {
                 r.register(m)?;
@@ -72,3 +314,100 @@ impl CustomMetrics {
         Ok((metrics, Box::new(f)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use prometheus::Registry;
+    use tokio::sync::Notify;
+
+    use super::{create_metrics, CustomMetrics};
+    use crate::config::{self, MetricsConfig, Readiness};
+    use crate::readings::Readings;
+    use crate::server::Probes;
+
+    fn names(config: &MetricsConfig) -> Vec<String> {
+        let registry = Registry::new();
+        let (metrics, f) = CustomMetrics::new(&[String::from("serial")], config).unwrap();
+        f(&registry).unwrap();
+        metrics.gauge_co2.unwrap().with_label_values(&["1"]).set(600);
+        metrics.counter_read_success.with_label_values(&["1"]).inc();
+        registry.gather().iter().map(|family| family.get_name().to_string()).collect()
+    }
+
+    #[test]
+    fn sensor_metrics_are_namespaced_with_units_unless_legacy() {
+        assert_eq!(
+            names(&MetricsConfig::default()),
+            vec![
+                "airthing_adapter_resets_total",
+                "airthing_adapters",
+                "airthing_build_info",
+                "airthing_co2_ppm",
+                "airthing_config_last_reload_timestamp_seconds",
+                "airthing_config_reload_errors_total",
+                "airthing_config_reload_total",
+                "airthing_devices_matched",
+                "airthing_peripherals_discovered",
+                "airthing_read_success_total",
+                "airthing_scan_cycle_duration_seconds",
+            ],
+        );
+
+        let legacy = MetricsConfig { legacy_names: true, ..MetricsConfig::default() };
+        assert_eq!(
+            names(&legacy),
+            vec![
+                "airthing_adapter_resets_total",
+                "airthing_adapters",
+                "airthing_build_info",
+                "airthing_config_last_reload_timestamp_seconds",
+                "airthing_config_reload_errors_total",
+                "airthing_config_reload_total",
+                "airthing_devices_matched",
+                "airthing_peripherals_discovered",
+                "airthing_read_success_total",
+                "airthing_scan_cycle_duration_seconds",
+                "co2",
+            ],
+        );
+
+        let renamed = MetricsConfig { namespace: String::from("home"), ..MetricsConfig::default() };
+        assert_eq!(
+            names(&renamed),
+            vec![
+                "home_adapter_resets_total",
+                "home_adapters",
+                "home_build_info",
+                "home_co2_ppm",
+                "home_config_last_reload_timestamp_seconds",
+                "home_config_reload_errors_total",
+                "home_config_reload_total",
+                "home_devices_matched",
+                "home_peripherals_discovered",
+                "home_read_success_total",
+                "home_scan_cycle_duration_seconds",
+            ],
+        );
+    }
+
+    #[test]
+    fn invalid_label_names_fail_instead_of_panicking() {
+        let config = config::parse_config("labels.toml", r#"
+            [2930025667]
+            "room name" = "Study"
+        "#).unwrap();
+
+        let created = create_metrics(
+            &config,
+            "127.0.0.1:0".parse().unwrap(),
+            Readings::default(),
+            Probes::new(Readiness::Any, vec![]),
+            Arc::new(Notify::new()),
+        );
+
+        let err = created.err().expect("the label name is rejected");
+        assert!(format!("{:#}", err).starts_with("failed creating metrics: "), "{:#}", err);
+    }
+}
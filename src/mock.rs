@@ -0,0 +1,138 @@
+//! Synthesized readings for running without Bluetooth hardware, e.g. to build dashboards or in CI.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::future;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+
+use crate::sensor::{SensorModel, SensorValues};
+use crate::source::{Advertisement, DeviceInfo, FailureReason, QueryError, Scan, SensorSource, WithReason};
+
+/// Readings handed out per serial, cycling through each device's list.
+pub struct MockReadings {
+    fixtures: HashMap<u32, Vec<SensorValues>>,
+    next: RefCell<HashMap<u32, usize>>,
+}
+
+impl MockReadings {
+    /// the same plausible Wave Plus reading for every serial
+    pub fn fixed(serials: impl IntoIterator<Item = u32>) -> MockReadings {
+        let values = SensorValues {
+            version: 1,
+            humidity: Some(45.5),
+            temp: Some(21.5),
+            atm: Some(1013.2),
+            radon_short: Some(40),
+            radon_long: Some(35),
+            co2: Some(600),
+            voc: Some(120),
+            battery: Some(90),
+        };
+        Self::new(serials.into_iter().map(|serial| (serial, vec![values.clone()])).collect())
+    }
+
+    /// json fixture, a list of readings per serial, e.g. `{"2930025667": [{"version": 1, "temp": 21.5}]}`
+    pub fn load(path: &str) -> Result<MockReadings> {
+        let json = fs::read_to_string(path).with_context(|| format!("failed to read mock fixture {}", path))?;
+        Self::parse(&json).with_context(|| format!("failed to parse mock fixture {}", path))
+    }
+
+    fn parse(json: &str) -> Result<MockReadings> {
+        Ok(Self::new(serde_json::from_str(json)?))
+    }
+
+    fn new(fixtures: HashMap<u32, Vec<SensorValues>>) -> MockReadings {
+        MockReadings { fixtures, next: RefCell::new(HashMap::new()) }
+    }
+
+    fn serials(&self) -> Vec<u32> {
+        let mut serials: Vec<u32> = self.fixtures.keys().copied().collect();
+        serials.sort_unstable();
+        serials
+    }
+
+    /// the device's next reading, starting over after the last one
+    fn next_values(&self, serial: u32) -> Option<SensorValues> {
+        let values = self.fixtures.get(&serial).filter(|values| !values.is_empty())?;
+        let mut next = self.next.borrow_mut();
+        let index = next.entry(serial).or_insert(0);
+        let current = values[*index % values.len()].clone();
+        *index += 1;
+        Some(current)
+    }
+}
+
+#[async_trait(?Send)]
+impl SensorSource for MockReadings {
+    async fn scan(&self) -> Result<Scan> {
+        let advertisements: Vec<Advertisement> = self.serials().into_iter()
+            .map(|serial| Advertisement { serial, rssi: None, adapter: None })
+            .collect();
+        Ok(Scan { peripherals: advertisements.len(), advertisements })
+    }
+
+    fn adapters(&self) -> usize {
+        0
+    }
+
+    async fn subscribe(&self, _serial: u32) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn subscribed(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    async fn next_notification(&self) -> (u32, SensorValues) {
+        future::pending().await
+    }
+
+    async fn read(&self, serial: u32) -> Result<SensorValues, QueryError> {
+        self.next_values(serial).ok_or_else(|| anyhow!("no mock readings for {}", serial)).reason(FailureReason::Read)
+    }
+
+    async fn read_raw(&self, serial: u32) -> Result<(SensorModel, Vec<u8>)> {
+        Err(anyhow!("mock readings for {} have no raw payload", serial))
+    }
+
+    async fn device_info(&self, serial: u32) -> Result<DeviceInfo> {
+        // airthings serials start with the model number
+        Ok(DeviceInfo {
+            model: Some(serial.to_string().chars().take(4).collect()),
+            firmware: Some(String::from("mock")),
+        })
+    }
+
+    async fn disconnect(&self, _serial: u32) -> Result<()> {
+        Ok(())
+    }
+
+    async fn restart_scan(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn disconnect_all(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockReadings;
+
+    #[test]
+    fn fixture_readings_cycle_per_serial() {
+        let mock = MockReadings::parse(r#"{
+            "1": [{"version": 1, "temp": 20.0}, {"version": 1, "temp": 21.0, "co2": 800}],
+            "2": [{"version": 1, "humidity": 40.0}]
+        }"#).unwrap();
+
+        assert_eq!(mock.serials(), vec![1, 2]);
+        assert_eq!(mock.next_values(1).unwrap().temp, Some(20.0));
+        assert_eq!(mock.next_values(1).unwrap().co2, Some(800));
+        assert_eq!(mock.next_values(1).unwrap().temp, Some(20.0));
+        assert_eq!(mock.next_values(2).unwrap().humidity, Some(40.0));
+        assert!(mock.next_values(3).is_none());
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use tokio::time;
+
+use crate::config::MqttConfig;
+use crate::readings::DeviceReading;
+use crate::sink::ReadingSink;
+
+/// Home Assistant entity description: reading field, entity name, unit and device class.
+type DiscoveryField = (&'static str, &'static str, &'static str, Option<&'static str>);
+
+const DISCOVERY_FIELDS: [DiscoveryField; 7] = [
+    ("radon_short", "Radon (24h average)", "Bq/m³", None),
+    ("radon_long", "Radon (long term average)", "Bq/m³", None),
+    ("temp", "Temperature", "°C", Some("temperature")),
+    ("humidity", "Humidity", "%", Some("humidity")),
+    ("atm", "Pressure", "mbar", Some("pressure")),
+    ("co2", "CO2", "ppm", Some("carbon_dioxide")),
+    ("voc", "VOC", "ppb", Some("volatile_organic_compounds_parts")),
+];
+
+struct MqttSink {
+    client: AsyncClient,
+    topic_prefix: String,
+    discovery_prefix: Option<String>,
+    /// fields announced since connecting, by serial and field
+    discovered: Arc<Mutex<HashSet<(u32, &'static str)>>>,
+}
+
+/// Connects to the broker in the background, readings published while disconnected are queued.
+/// Discovery is announced again after every connect, a broker restarted without persistence lost it.
+pub fn new_mqtt_sink(config: &MqttConfig) -> Box<dyn ReadingSink> {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+    let discovered = Arc::new(Mutex::new(HashSet::new()));
+    let connected = Arc::clone(&discovered);
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    debug!("connected to mqtt");
+                    connected.lock().unwrap().clear();
+                }
+                Ok(event) => debug!("mqtt event: {:?}", event),
+                Err(err) => {
+                    warn!("mqtt connection failed, retrying: {}", err);
+                    time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+
+    Box::new(MqttSink {
+        client,
+        topic_prefix: config.topic_prefix.clone(),
+        discovery_prefix: if config.discovery { Some(config.discovery_prefix.clone()) } else { None },
+        discovered,
+    })
+}
+
+impl MqttSink {
+    fn state_topic(&self, serial: u32) -> String {
+        format!("{}/{}/state", self.topic_prefix, serial)
+    }
+
+    /// announces the fields of a device to home assistant once they have a value, once per field and connection
+    fn publish_discovery(&self, discovery_prefix: &str, reading: &DeviceReading, state: &Value) {
+        let fields = self.undiscovered(reading.serial, state);
+        if fields.is_empty() {
+            return;
+        }
+
+        let device_name = device_name(reading);
+        info!("publishing home assistant discovery for {} ({})", reading.serial, device_name);
+        for (field, name, unit, device_class) in fields {
+            let mut config = json!({
+                "name": format!("{} {}", device_name, name),
+                "unique_id": format!("airthing_{}_{}", reading.serial, field),
+                "state_topic": self.state_topic(reading.serial),
+                "value_template": format!("{{{{ value_json.{} }}}}", field),
+                "unit_of_measurement": unit,
+                "state_class": "measurement",
+                "device": {
+                    "identifiers": [format!("airthing_{}", reading.serial)],
+                    "name": device_name,
+                    "manufacturer": "Airthings",
+                },
+            });
+            if let Some(device_class) = device_class {
+                config["device_class"] = json!(device_class);
+            }
+
+            let topic = format!("{}/sensor/{}_{}/config", discovery_prefix, reading.serial, field);
+            if let Err(err) = self.client.try_publish(topic, QoS::AtLeastOnce, true, config.to_string()) {
+                warn!("failed to publish discovery for {} to mqtt: {}", reading.serial, err);
+                self.discovered.lock().unwrap().remove(&(reading.serial, *field));
+            }
+        }
+    }
+
+    /// the fields with a value not announced yet, marked as announced
+    fn undiscovered(&self, serial: u32, state: &Value) -> Vec<&'static DiscoveryField> {
+        let mut discovered = self.discovered.lock().unwrap();
+        DISCOVERY_FIELDS.iter()
+            .filter(|(field, ..)| !state[*field].is_null() && discovered.insert((serial, *field)))
+            .collect()
+    }
+}
+
+/// configured labels other than the serial, or the serial itself if there are none
+fn device_name(reading: &DeviceReading) -> String {
+    let labels: Vec<&str> = reading.labels.iter()
+        .filter(|(name, value)| name.as_str() != "serial" && !value.is_empty())
+        .map(|(_, value)| value.as_str())
+        .collect();
+
+    if labels.is_empty() {
+        format!("Airthings {}", reading.serial)
+    } else {
+        labels.join(" ")
+    }
+}
+
+impl ReadingSink for MqttSink {
+    fn publish(&self, reading: &DeviceReading) {
+        let state = match serde_json::to_value(reading) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("failed to serialize reading for {}: {}", reading.serial, err);
+                return;
+            }
+        };
+
+        if let Some(discovery_prefix) = &self.discovery_prefix {
+            self.publish_discovery(discovery_prefix, reading, &state);
+        }
+
+        let topic = self.state_topic(reading.serial);
+        if let Err(err) = self.client.try_publish(topic, QoS::AtLeastOnce, false, state.to_string()) {
+            warn!("failed to publish reading for {} to mqtt: {}", reading.serial, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    use rumqttc::{AsyncClient, MqttOptions};
+    use serde_json::json;
+
+    use super::MqttSink;
+
+    #[test]
+    fn fields_are_announced_once_they_have_a_value() {
+        let (client, _event_loop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 64);
+        let sink = MqttSink {
+            client,
+            topic_prefix: String::from("airthing"),
+            discovery_prefix: Some(String::from("homeassistant")),
+            discovered: Arc::new(Mutex::new(HashSet::new())),
+        };
+        let names = |fields: Vec<&super::DiscoveryField>| fields.iter().map(|(field, ..)| *field).collect::<Vec<_>>();
+
+        let first = json!({"radon_short": 40, "temp": 21.5, "co2": null});
+        assert_eq!(names(sink.undiscovered(1, &first)), vec!["radon_short", "temp"]);
+        assert!(sink.undiscovered(1, &first).is_empty());
+
+        let later = json!({"radon_short": 41, "temp": 21.5, "co2": 600});
+        assert_eq!(names(sink.undiscovered(1, &later)), vec!["co2"], "a later reading fills the gap");
+        assert_eq!(names(sink.undiscovered(2, &later)), vec!["radon_short", "temp", "co2"]);
+    }
+}
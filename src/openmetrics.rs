@@ -0,0 +1,162 @@
+//! The OpenMetrics text format, served on `/metrics` to clients asking for it; the prometheus crate only
+//! encodes the classic text format.
+
+use std::fmt::Write as _;
+use std::io::Write;
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::Encoder;
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+pub struct OpenMetricsEncoder;
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(&self, metric_families: &[MetricFamily], writer: &mut W) -> prometheus::Result<()> {
+        writer.write_all(exposition(metric_families).as_bytes())?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn exposition(metric_families: &[MetricFamily]) -> String {
+    let mut text = String::new();
+    for family in metric_families {
+        let metric_type = family.get_field_type();
+        // counter samples end in `_total`, their family does not
+        let name = match metric_type {
+            MetricType::COUNTER => family.get_name().strip_suffix("_total").unwrap_or(family.get_name()),
+            _ => family.get_name(),
+        };
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+        if !family.get_help().is_empty() {
+            let _ = writeln!(text, "# HELP {} {}", name, escape(family.get_help()));
+        }
+        let _ = writeln!(text, "# TYPE {} {}", name, type_name);
+
+        for metric in family.get_metric() {
+            let mut sample = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                write_sample(&mut text, name, suffix, metric, extra, value)
+            };
+            match metric_type {
+                MetricType::COUNTER => sample("_total", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => sample("", None, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => sample("", None, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut inf_seen = false;
+                    for bucket in histogram.get_bucket() {
+                        let upper_bound = bucket.get_upper_bound();
+                        inf_seen |= upper_bound == f64::INFINITY;
+                        sample("_bucket", Some(("le", number(upper_bound))), bucket.get_cumulative_count() as f64);
+                    }
+                    if !inf_seen {
+                        sample("_bucket", Some(("le", number(f64::INFINITY))), histogram.get_sample_count() as f64);
+                    }
+                    sample("_sum", None, histogram.get_sample_sum());
+                    sample("_count", None, histogram.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        sample("", Some(("quantile", number(quantile.get_quantile()))), quantile.get_value());
+                    }
+                    sample("_sum", None, summary.get_sample_sum());
+                    sample("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    text.push_str("# EOF\n");
+    text
+}
+
+fn write_sample(
+    text: &mut String,
+    name: &str,
+    suffix: &str,
+    metric: &Metric,
+    extra: Option<(&str, String)>,
+    value: f64,
+) {
+    let _ = write!(text, "{}{}", name, suffix);
+    let mut labels: Vec<String> = metric.get_label().iter().map(label).collect();
+    if let Some((name, value)) = extra {
+        labels.push(format!("{}=\"{}\"", name, value));
+    }
+    if !labels.is_empty() {
+        let _ = write!(text, "{{{}}}", labels.join(","));
+    }
+    let _ = write!(text, " {}", number(value));
+    // in seconds, unlike the classic format
+    if metric.get_timestamp_ms() != 0 {
+        let _ = write!(text, " {}", metric.get_timestamp_ms() as f64 / 1000.0);
+    }
+    text.push('\n');
+}
+
+fn label(pair: &LabelPair) -> String {
+    format!("{}=\"{}\"", pair.get_name(), escape(pair.get_value()))
+}
+
+fn number(value: f64) -> String {
+    if value == f64::INFINITY {
+        String::from("+Inf")
+    } else if value == f64::NEG_INFINITY {
+        String::from("-Inf")
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+
+    use super::OpenMetricsEncoder;
+
+    #[test]
+    fn families_are_encoded_as_openmetrics() {
+        let registry = Registry::new();
+        let reads = IntCounterVec::new(Opts::new("reads_total", "reads, by \"serial\""), &["serial"]).unwrap();
+        let adapters = IntGauge::new("adapters", "adapters scanned on").unwrap();
+        let cycle = Histogram::with_opts(HistogramOpts::new("cycle_seconds", "cycle").buckets(vec![0.5, 2.0])).unwrap();
+        registry.register(Box::new(reads.clone())).unwrap();
+        registry.register(Box::new(adapters.clone())).unwrap();
+        registry.register(Box::new(cycle.clone())).unwrap();
+        reads.with_label_values(&["2930025667"]).inc();
+        adapters.set(2);
+        cycle.observe(1.0);
+
+        let mut buffer = Vec::new();
+        OpenMetricsEncoder.encode(&registry.gather(), &mut buffer).unwrap();
+
+        assert_eq!(String::from_utf8(buffer).unwrap(), "# HELP adapters adapters scanned on\n\
+            # TYPE adapters gauge\n\
+            adapters 2\n\
+            # HELP cycle_seconds cycle\n\
+            # TYPE cycle_seconds histogram\n\
+            cycle_seconds_bucket{le=\"0.5\"} 0\n\
+            cycle_seconds_bucket{le=\"2\"} 1\n\
+            cycle_seconds_bucket{le=\"+Inf\"} 1\n\
+            cycle_seconds_sum 1\n\
+            cycle_seconds_count 1\n\
+            # HELP reads reads, by \\\"serial\\\"\n\
+            # TYPE reads counter\n\
+            reads_total{serial=\"2930025667\"} 1\n\
+            # EOF\n");
+    }
+}
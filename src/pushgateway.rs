@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use log::{debug, warn};
+use prometheus::proto::MetricFamily;
+use prometheus::{BasicAuthentication, Registry};
+use tokio::time;
+
+use crate::config::PushgatewayConfig;
+
+/// Pushes the registry every interval, one group per device so a device going stale only replaces its own metrics,
+/// and the metrics of the exporter itself in the job's group. The group of a device whose metrics are gone, as its
+/// control was dropped or it went stale, is deleted rather than left to be served forever.
+pub fn spawn_pusher(config: PushgatewayConfig, registry: Arc<Registry>) {
+    tokio::spawn(async move {
+        let client = Client::new();
+        let mut interval = time::interval(Duration::from_secs(config.interval));
+        let mut pushed = BTreeSet::new();
+        loop {
+            interval.tick().await;
+            let groups = group_by_serial(registry.gather());
+            let serials: BTreeSet<String> = groups.keys().flatten().cloned().collect();
+            for serial in pushed.difference(&serials) {
+                if let Err(err) = delete(&client, &config, serial).await {
+                    warn!("failed to delete the metrics of {} from {}: {:#}", serial, config.url, err);
+                }
+            }
+            pushed = serials;
+
+            for (serial, families) in groups {
+                let config = config.clone();
+                let url = config.url.clone();
+                // the push client blocks
+                let result = tokio::task::spawn_blocking(move || push(&config, serial.as_deref(), families))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|pushed| pushed.map_err(anyhow::Error::from));
+                if let Err(err) = result {
+                    warn!("failed to push metrics to {}: {:#}", url, err);
+                }
+            }
+        }
+    });
+}
+
+/// to the group of `serial`, or without one to the job's
+fn push(config: &PushgatewayConfig, serial: Option<&str>, families: Vec<MetricFamily>) -> prometheus::Result<()> {
+    let mut grouping = HashMap::new();
+    if let Some(serial) = serial {
+        grouping.insert(String::from("serial"), serial.to_string());
+    }
+    let auth = match (&config.username, &config.password) {
+        (Some(username), Some(password)) => Some(BasicAuthentication {
+            username: username.clone(),
+            password: password.clone(),
+        }),
+        _ => None,
+    };
+    prometheus::push_metrics(&config.job, grouping, &config.url, families, auth)?;
+    debug!("pushed metrics of {} to {}", serial.unwrap_or(&config.job), config.url);
+    Ok(())
+}
+
+/// the push client has no delete, it is a plain http request
+async fn delete(
+    client: &Client<HttpConnector>,
+    config: &PushgatewayConfig,
+    serial: &str,
+) -> anyhow::Result<()> {
+    let mut request = Request::builder().method(Method::DELETE).uri(group_url(config, serial));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        let credentials = STANDARD.encode(format!("{}:{}", username, password));
+        request = request.header("Authorization", format!("Basic {}", credentials));
+    }
+    let response = client.request(request.body(Body::empty())?).await?;
+    if !response.status().is_success() {
+        anyhow::bail!("unexpected status {}", response.status());
+    }
+    debug!("deleted metrics of {} from {}", serial, config.url);
+    Ok(())
+}
+
+fn group_url(config: &PushgatewayConfig, serial: &str) -> String {
+    let url = config.url.trim_end_matches('/');
+    let scheme = if url.contains("://") { "" } else { "http://" };
+    format!("{}{}/metrics/job/{}/serial/{}", scheme, url, config.job, serial)
+}
+
+/// splits metrics by their serial label, which is dropped as the pushgateway adds it back as grouping label,
+/// metrics without one under `None`
+fn group_by_serial(families: Vec<MetricFamily>) -> BTreeMap<Option<String>, Vec<MetricFamily>> {
+    let mut groups: BTreeMap<Option<String>, Vec<MetricFamily>> = BTreeMap::new();
+    for mut family in families {
+        for mut metric in family.take_metric().into_iter() {
+            let serial = metric.get_label().iter()
+                .find(|label| label.get_name() == "serial")
+                .map(|label| label.get_value().to_string());
+            metric.mut_label().retain(|label| label.get_name() != "serial");
+
+            let group = groups.entry(serial).or_default();
+            match group.iter_mut().find(|grouped| grouped.get_name() == family.get_name()) {
+                Some(grouped) => grouped.mut_metric().push(metric),
+                None => {
+                    let mut grouped = family.clone();
+                    grouped.mut_metric().push(metric);
+                    group.push(grouped);
+                }
+            }
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{IntGauge, IntGaugeVec, Opts, Registry};
+
+    use super::{group_by_serial, group_url};
+    use crate::config::PushgatewayConfig;
+
+    #[test]
+    fn metrics_are_grouped_by_serial() {
+        let registry = Registry::new();
+        let co2 = IntGaugeVec::new(Opts::new("co2", "in ppm"), &["serial", "room"]).unwrap();
+        registry.register(Box::new(co2.clone())).unwrap();
+        co2.with_label_values(&["1", "Study"]).set(600);
+        co2.with_label_values(&["2", "Bedroom"]).set(900);
+        let adapters = IntGauge::new("adapters", "bluetooth adapters").unwrap();
+        registry.register(Box::new(adapters.clone())).unwrap();
+        adapters.set(1);
+
+        let groups = group_by_serial(registry.gather());
+
+        let serials: Vec<Option<&str>> = groups.keys().map(Option::as_deref).collect();
+        assert_eq!(serials, vec![None, Some("1"), Some("2")]);
+        assert_eq!(groups[&None][0].get_name(), "adapters", "pushed to the job's group");
+        let metrics = groups[&Some(String::from("2"))][0].get_metric();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].get_gauge().get_value(), 900.0);
+        let labels: Vec<&str> = metrics[0].get_label().iter().map(|label| label.get_name()).collect();
+        assert_eq!(labels, vec!["room"]);
+    }
+
+    #[test]
+    fn groups_are_deleted_where_they_were_pushed() {
+        let config = |url: &str| PushgatewayConfig {
+            url: url.to_string(),
+            job: String::from("airthing"),
+            interval: 60,
+            username: None,
+            password: None,
+        };
+
+        assert_eq!(group_url(&config("http://pushgateway.local:9091/"), "1"),
+            "http://pushgateway.local:9091/metrics/job/airthing/serial/1");
+        assert_eq!(group_url(&config("pushgateway.local:9091"), "1"),
+            "http://pushgateway.local:9091/metrics/job/airthing/serial/1");
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::sensor::SensorValues;
+use crate::sink::ReadingSink;
+
+/// Latest values read from a device, as served on `/devices`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceReading {
+    pub serial: u32,
+    pub labels: BTreeMap<String, String>,
+    /// unix timestamp of the read, in seconds
+    pub last_seen: u64,
+    #[serde(flatten)]
+    pub values: SensorValues,
+}
+
+impl DeviceReading {
+    pub fn new(serial: u32, labels: &BTreeMap<String, String>, time: SystemTime, values: &SensorValues) -> DeviceReading {
+        DeviceReading {
+            serial,
+            labels: labels.clone(),
+            last_seen: time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            values: values.clone(),
+        }
+    }
+
+    /// For sink tests: humidity 45.5 %, temperature 21.25 C, radon 40 Bq/m³ short term and co2 600 ppm,
+    /// read at 1600000000 (2020-09-13 12:26:40 UTC), labelled with its serial and `labels`.
+    #[cfg(test)]
+    pub fn fixture(serial: u32, labels: &[(&str, &str)]) -> DeviceReading {
+        let mut label_map: BTreeMap<String, String> =
+            labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        label_map.insert(String::from("serial"), serial.to_string());
+        let values = SensorValues {
+            version: 1,
+            humidity: Some(45.5),
+            temp: Some(21.25),
+            atm: None,
+            radon_short: Some(40),
+            radon_long: None,
+            co2: Some(600),
+            voc: None,
+            battery: None,
+        };
+        DeviceReading::new(serial, &label_map, UNIX_EPOCH + std::time::Duration::from_secs(1600000000), &values)
+    }
+}
+
+/// readings a slow live subscriber may fall behind by before it misses some
+const UPDATES_CAPACITY: usize = 64;
+
+/// Latest reading per device, shared between the query loop and the http server,
+/// new readings are also broadcast to live subscribers.
+#[derive(Clone)]
+pub struct Readings {
+    devices: Arc<RwLock<HashMap<u32, DeviceReading>>>,
+    updates: broadcast::Sender<DeviceReading>,
+}
+
+impl Default for Readings {
+    fn default() -> Self {
+        Readings {
+            devices: Arc::default(),
+            updates: broadcast::channel(UPDATES_CAPACITY).0,
+        }
+    }
+}
+
+impl Readings {
+    /// every reading published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceReading> {
+        self.updates.subscribe()
+    }
+
+    /// serials of all devices read so far
+    pub fn serials(&self) -> HashSet<u32> {
+        self.devices.read().unwrap().keys().copied().collect()
+    }
+
+
+    /// all readings, ordered by serial
+    pub fn all(&self) -> Vec<DeviceReading> {
+        let mut readings: Vec<DeviceReading> = self.devices.read().unwrap().values().cloned().collect();
+        readings.sort_by_key(|reading| reading.serial);
+        readings
+    }
+}
+
+impl ReadingSink for Readings {
+    fn publish(&self, reading: &DeviceReading) {
+        self.devices.write().unwrap().insert(reading.serial, reading.clone());
+        // fails only without subscribers
+        let _ = self.updates.send(reading.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use std::collections::BTreeMap;
+
+    use super::{DeviceReading, Readings};
+    use crate::sensor::{SensorModel, SensorValues};
+    use crate::sink::ReadingSink;
+
+    fn labels(serial: &str, room: &str) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::new();
+        labels.insert(String::from("serial"), String::from(serial));
+        labels.insert(String::from("room"), String::from(room));
+        labels
+    }
+
+    #[test]
+    fn readings_are_served_as_json_ordered_by_serial() {
+        let readings = Readings::default();
+        let mut data = vec![0; 16];
+        data[0] = 1;
+        let values = SensorValues::from_vec(SensorModel::WavePlus, data).unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1000);
+
+        readings.publish(&DeviceReading::new(2, &labels("2", "Study"), time, &values));
+        readings.publish(&DeviceReading::new(1, &labels("1", "Bedroom"), time, &values));
+
+        let json = serde_json::to_value(readings.all()).unwrap();
+        assert_eq!(json[0]["serial"], 1);
+        assert_eq!(json[0]["labels"]["room"], "Bedroom");
+        assert_eq!(json[0]["last_seen"], 1000);
+        assert_eq!(json[1]["serial"], 2);
+        assert_eq!(json[1]["radon_short"], 0);
+    }
+}
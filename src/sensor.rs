@@ -1,35 +1,178 @@
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
 use bytes::{Buf, Bytes};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
 
-pub fn parse_serial(manufacturer_data: HashMap<u16, Vec<u8>>) -> Option<u32> {
-    manufacturer_data.get(&820).map(|md| {
-        ((md[3] as u32) << 24) +
-        ((md[2] as u32) << 16) +
-        ((md[1] as u32) << 8) +
-        (md[0] as u32)
-    })
+/// Bluetooth SIG company identifier of Airthings, 820
+pub const AIRTHINGS_COMPANY_ID: u16 = 0x0334;
+
+/// The serial leads the manufacturer data of the first of `company_ids` present, little endian.
+/// `None` for other advertisers, and for data too short to hold one, as sent by devices misusing a company id.
+pub fn parse_serial(manufacturer_data: HashMap<u16, Vec<u8>>, company_ids: &[u16]) -> Option<u32> {
+    let data = company_ids.iter().find_map(|company_id| manufacturer_data.get(company_id))?;
+    let serial = data.get(..4)?;
+    Some(u32::from_le_bytes([serial[0], serial[1], serial[2], serial[3]]))
+}
+
+/// Manufacturer data by company id, in decimal as in `company_ids`, bytes in hex, for telling why a device
+/// has no serial parsed, e.g. `820: 43 2b a4 ae 09 00`
+pub fn manufacturer_data_hex(manufacturer_data: &HashMap<u16, Vec<u8>>) -> String {
+    let mut company_ids: Vec<&u16> = manufacturer_data.keys().collect();
+    company_ids.sort();
+    let entries: Vec<String> = company_ids.into_iter()
+        .map(|company_id| {
+            let hex: Vec<String> = manufacturer_data[company_id].iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{}: {}", company_id, hex.join(" "))
+        })
+        .collect();
+    entries.join(", ")
+}
+
+/// Airthings device families, told apart by the sensor values characteristic they expose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorModel {
+    /// Wave Plus: radon, temperature, humidity, pressure, co2 and voc
+    WavePlus,
+    /// Wave (2nd gen): radon, temperature and humidity
+    Wave,
+    /// Wave Mini: temperature, humidity and voc
+    WaveMini,
+    /// Wave (1st gen): radon, temperature and humidity, each through a characteristic of its own
+    WaveGen1,
+}
+
+/// Battery Level characteristic (0x2A19) of the standard Battery Service (0x180F)
+pub const BATTERY_LEVEL_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00002a19_0000_1000_8000_00805f9b34fb);
+/// Model Number String (0x2A24) and Firmware Revision String (0x2A26) of the Device Information Service (0x180A)
+pub const MODEL_NUMBER_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00002a24_0000_1000_8000_00805f9b34fb);
+pub const FIRMWARE_REVISION_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00002a26_0000_1000_8000_00805f9b34fb);
+
+const WAVE_PLUS_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xb42e2a68_ade7_11e4_89d3_123b93f75cba);
+const WAVE_PLUS_SERVICE_UUID: Uuid = Uuid::from_u128(0xb42e1c08_ade7_11e4_89d3_123b93f75cba);
+const WAVE_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xb42e4dcc_ade7_11e4_89d3_123b93f75cba);
+const WAVE_SERVICE_UUID: Uuid = Uuid::from_u128(0xb42e4a8e_ade7_11e4_89d3_123b93f75cba);
+const WAVE_MINI_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xb42e3b98_ade7_11e4_89d3_123b93f75cba);
+const WAVE_MINI_SERVICE_UUID: Uuid = Uuid::from_u128(0xb42e3882_ade7_11e4_89d3_123b93f75cba);
+/// first generation Wave radon averages, with the standard Temperature (0x2A6E) and Humidity (0x2A6F)
+const WAVE_GEN1_RADON_SHORT_UUID: Uuid = Uuid::from_u128(0xb42e01aa_ade7_11e4_89d3_123b93f75cba);
+const WAVE_GEN1_RADON_LONG_UUID: Uuid = Uuid::from_u128(0xb42e0a4c_ade7_11e4_89d3_123b93f75cba);
+const TEMPERATURE_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00002a6e_0000_1000_8000_00805f9b34fb);
+const HUMIDITY_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00002a6f_0000_1000_8000_00805f9b34fb);
+const WAVE_GEN1_SERVICE_UUID: Uuid = Uuid::from_u128(0xb42e1f6e_ade7_11e4_89d3_123b93f75cba);
+
+impl SensorModel {
+    pub const ALL: [SensorModel; 4] =
+        [SensorModel::WavePlus, SensorModel::Wave, SensorModel::WaveMini, SensorModel::WaveGen1];
+
+    /// the model a device exposing `uuids` is, in the order of `ALL` as a device may also expose the
+    /// characteristics of older models
+    pub fn from_characteristics(uuids: &[Uuid]) -> Option<SensorModel> {
+        Self::ALL.iter().copied().find(|model| uuids.contains(&model.characteristic_uuid()))
+    }
+
+    /// the characteristic the model is recognized by, the first of `value_characteristics`
+    pub fn characteristic_uuid(&self) -> Uuid {
+        match self {
+            SensorModel::WavePlus => WAVE_PLUS_CHARACTERISTIC_UUID,
+            SensorModel::Wave => WAVE_CHARACTERISTIC_UUID,
+            SensorModel::WaveMini => WAVE_MINI_CHARACTERISTIC_UUID,
+            SensorModel::WaveGen1 => WAVE_GEN1_RADON_SHORT_UUID,
+        }
+    }
+
+    /// characteristics the payload is read from, in order. The first generation Wave's payload is its
+    /// four 2 byte values put together: radon short and long term average, temperature and humidity
+    pub fn value_characteristics(&self) -> Vec<Uuid> {
+        match self {
+            SensorModel::WaveGen1 => vec![
+                WAVE_GEN1_RADON_SHORT_UUID,
+                WAVE_GEN1_RADON_LONG_UUID,
+                TEMPERATURE_CHARACTERISTIC_UUID,
+                HUMIDITY_CHARACTERISTIC_UUID,
+            ],
+            _ => vec![self.characteristic_uuid()],
+        }
+    }
+
+    /// number of payload bytes the parser consumes
+    pub fn payload_len(&self) -> usize {
+        match self {
+            SensorModel::WavePlus => 16,
+            SensorModel::Wave => 10,
+            SensorModel::WaveMini => 10,
+            SensorModel::WaveGen1 => 8,
+        }
+    }
+
+    /// payload format version the parser understands, `None` if the model does not report a known one
+    pub fn supported_version(&self) -> Option<u8> {
+        match self {
+            SensorModel::WavePlus => Some(1),
+            SensorModel::Wave => Some(1),
+            SensorModel::WaveMini => None,
+            SensorModel::WaveGen1 => None,
+        }
+    }
+
+    pub fn service_uuid(&self) -> Uuid {
+        match self {
+            SensorModel::WavePlus => WAVE_PLUS_SERVICE_UUID,
+            SensorModel::Wave => WAVE_SERVICE_UUID,
+            SensorModel::WaveMini => WAVE_MINI_SERVICE_UUID,
+            SensorModel::WaveGen1 => WAVE_GEN1_SERVICE_UUID,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("{model:?} payload too short: expected {expected} bytes, got {actual}")]
+    TooShort { model: SensorModel, expected: usize, actual: usize },
+    #[error("{model:?} payload has unsupported version {version}, expected {expected}")]
+    UnsupportedVersion { model: SensorModel, version: u8, expected: u8 },
+}
+
+/// Values read from a device, fields the model does not measure are `None`.
+/// None of the payloads say when the values were measured, a read may return the same values as the one before
+/// until the device measures again, see `gauge_last_change` for when they last changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SensorValues {
-    pub humidity: f32,
-    pub temp: f32,
-    pub atm: f32,
-    pub radon_short: u16,
-    pub radon_long: u16,
-    pub co2: u16,
-    pub voc: u16,
+    /// payload format version, the first byte of the payload
+    #[serde(default)]
+    pub version: u8,
+    pub humidity: Option<f32>,
+    pub temp: Option<f32>,
+    pub atm: Option<f32>,
+    /// average over the past 24 hours, in Bq/m³
+    pub radon_short: Option<u16>,
+    /// average since the device started measuring, i.e. since its batteries went in or it was reset, in Bq/m³
+    pub radon_long: Option<u16>,
+    pub co2: Option<u16>,
+    pub voc: Option<u16>,
+    /// in %, read from the standard battery service rather than the payload
+    pub battery: Option<u8>,
 }
 
+/// NaN counts as equal to NaN, a device stuck reporting garbage has not changed its value
+fn floats_differ(this: Option<f32>, other: Option<f32>) -> bool {
+    match (this, other) {
+        (Some(this), Some(other)) => this != other && !(this.is_nan() && other.is_nan()),
+        (this, other) => this.is_some() != other.is_some(),
+    }
+}
+
+/// Compares the measured values only, the battery draining does not make a reading new.
 impl PartialEq for SensorValues {
     fn eq(&self, other: &Self) -> bool {
-        if self.humidity != other.humidity && (!self.humidity.is_nan() || other.humidity.is_nan()) {
+        if floats_differ(self.humidity, other.humidity) {
             return false;
         }
-        if self.temp != other.temp && (!self.temp.is_nan() || other.temp.is_nan()) {
+        if floats_differ(self.temp, other.temp) {
             return false;
         }
-        if self.atm != other.atm && (!self.atm.is_nan() || other.atm.is_nan()) {
+        if floats_differ(self.atm, other.atm) {
             return false;
         }
 
@@ -46,17 +189,166 @@ impl PartialEq for SensorValues {
             return false;
         }
 
-        return true;
+        true
     }
 }
 
 impl Eq for SensorValues {}
 
+/// Plausible value ranges, readings outside are placeholders like 0xFFFF or NaN rather than measurements:
+/// humidity 0-100 %, temperature -40-85 C (beyond what the devices operate in), pressure 300-1100 mbar,
+/// radon 0-16383 Bq/m³ (the sensor maximum), co2 0-10000 ppm, voc 0-10000 ppb and battery 0-100 %.
+const HUMIDITY_RANGE: RangeInclusive<f32> = 0.0..=100.0;
+const TEMP_RANGE: RangeInclusive<f32> = -40.0..=85.0;
+const ATM_RANGE: RangeInclusive<f32> = 300.0..=1100.0;
+const RADON_RANGE: RangeInclusive<u16> = 0..=16383;
+const CO2_RANGE: RangeInclusive<u16> = 0..=10000;
+const VOC_RANGE: RangeInclusive<u16> = 0..=10000;
+const BATTERY_RANGE: RangeInclusive<u8> = 0..=100;
+
+/// Clears `value` if it is out of `range`, noting `name` in `implausible`.
+fn keep_plausible<T: PartialOrd>(
+    value: &mut Option<T>,
+    range: &RangeInclusive<T>,
+    name: &'static str,
+    implausible: &mut Vec<&'static str>,
+) {
+    if value.as_ref().is_some_and(|value| !range.contains(value)) {
+        *value = None;
+        implausible.push(name);
+    }
+}
+
+/// Magnus formula coefficients by Sonntag (1990), accurate within 0.35 C from -45 to 60 C
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+
+/// saturation vapour pressure by Bolton (1980), in hPa, for absolute humidity
+fn saturation_vapour_pressure(temp: f32) -> f32 {
+    6.112 * (17.67 * temp / (temp + 243.5)).exp()
+}
+
+/// 100 Pa/hPa * 1000 g/kg over the gas constant of water vapour, 461.4 J/(kg K)
+const WATER_VAPOUR_G_K_PER_M3_HPA: f32 = 216.74;
+
+/// 1 pCi/L is 37 Bq/m³
+const BQ_PER_PCIL: f64 = 37.0;
+
+pub fn bq_to_pcil(bq: u16) -> f64 {
+    bq as f64 / BQ_PER_PCIL
+}
+
+/// Airthings' thresholds, values below the first are good, below the second fair and poor from there on
+const CO2_LEVELS_PPM: [u16; 2] = [800, 1000];
+const VOC_LEVELS_PPB: [u16; 2] = [250, 2000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AirQuality {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl AirQuality {
+    fn from_levels(value: u16, levels: [u16; 2]) -> AirQuality {
+        if value < levels[0] {
+            AirQuality::Good
+        } else if value < levels[1] {
+            AirQuality::Fair
+        } else {
+            AirQuality::Poor
+        }
+    }
+
+    /// 0 for good up to 2 for poor
+    pub fn severity(self) -> i64 {
+        self as i64
+    }
+}
+
 impl SensorValues {
-    pub fn from_vec(data: Vec<u8>) -> SensorValues {
-        let mut bytes = Bytes::from(data);
+    /// drops values outside their plausible range, so they don't overwrite good ones, and names the dropped fields
+    pub fn remove_implausible(&mut self) -> Vec<&'static str> {
+        let mut implausible = Vec::new();
+        keep_plausible(&mut self.humidity, &HUMIDITY_RANGE, "humidity", &mut implausible);
+        keep_plausible(&mut self.temp, &TEMP_RANGE, "temp", &mut implausible);
+        keep_plausible(&mut self.atm, &ATM_RANGE, "atm", &mut implausible);
+        keep_plausible(&mut self.radon_short, &RADON_RANGE, "radon_short", &mut implausible);
+        keep_plausible(&mut self.radon_long, &RADON_RANGE, "radon_long", &mut implausible);
+        keep_plausible(&mut self.co2, &CO2_RANGE, "co2", &mut implausible);
+        keep_plausible(&mut self.voc, &VOC_RANGE, "voc", &mut implausible);
+        keep_plausible(&mut self.battery, &BATTERY_RANGE, "battery", &mut implausible);
+        implausible
+    }
 
-        bytes.advance(1);
+    /// 24 hour radon average in pCi/L, the unit US guidelines use
+    pub fn radon_short_pcil(&self) -> Option<f64> {
+        self.radon_short.map(bq_to_pcil)
+    }
+
+    /// radon average since the device started measuring, in pCi/L
+    pub fn radon_long_pcil(&self) -> Option<f64> {
+        self.radon_long.map(bq_to_pcil)
+    }
+
+    /// in C, `None` without both temperature and humidity or at 0% humidity where it is undefined
+    pub fn dew_point(&self) -> Option<f32> {
+        let (temp, humidity) = (self.temp?, self.humidity?);
+        if humidity <= 0.0 {
+            return None;
+        }
+        let gamma = (humidity / 100.0).ln() + MAGNUS_A * temp / (MAGNUS_B + temp);
+        Some(MAGNUS_B * gamma / (MAGNUS_A - gamma))
+    }
+
+    /// in g/m³, comparable across temperatures unlike relative humidity
+    pub fn absolute_humidity(&self) -> Option<f32> {
+        let (temp, humidity) = (self.temp?, self.humidity?);
+        let vapour_pressure = saturation_vapour_pressure(temp) * humidity / 100.0;
+        Some(WATER_VAPOUR_G_K_PER_M3_HPA * vapour_pressure / (temp + 273.15))
+    }
+
+    pub fn co2_level(&self) -> Option<AirQuality> {
+        self.co2.map(|co2| AirQuality::from_levels(co2, CO2_LEVELS_PPM))
+    }
+
+    pub fn voc_level(&self) -> Option<AirQuality> {
+        self.voc.map(|voc| AirQuality::from_levels(voc, VOC_LEVELS_PPB))
+    }
+
+    /// relative humidity as a fraction of 1
+    pub fn humidity_ratio(&self) -> Option<f64> {
+        self.humidity.map(|percent| f64::from(percent) / 100.0)
+    }
+
+    pub fn temp_fahrenheit(&self) -> Option<f32> {
+        self.temp.map(|celsius| celsius * 9.0 / 5.0 + 32.0)
+    }
+
+    pub fn from_vec(model: SensorModel, data: Vec<u8>) -> Result<SensorValues, ParseError> {
+        if data.len() < model.payload_len() {
+            return Err(ParseError::TooShort { model, expected: model.payload_len(), actual: data.len() });
+        }
+        let version = data[0];
+        if let Some(expected) = model.supported_version() {
+            if version != expected {
+                return Err(ParseError::UnsupportedVersion { model, version, expected });
+            }
+        }
+        let bytes = Bytes::from(data);
+
+        Ok(match model {
+            SensorModel::WavePlus => Self::parse_wave_plus(bytes),
+            SensorModel::Wave => Self::parse_wave(bytes),
+            SensorModel::WaveMini => Self::parse_wave_mini(bytes),
+            SensorModel::WaveGen1 => Self::parse_wave_gen1(bytes),
+        })
+    }
+
+    fn parse_wave_plus(mut bytes: Bytes) -> SensorValues {
+        let version = bytes.get_u8();
+        // in half percent as in Airthings' read_waveplus.py, a byte above 200 is out of range rather than
+        // another scale, see remove_implausible
         let humidity = (bytes.get_u8() as f32) / 2.0;
         bytes.advance(2);
         let radon_short = bytes.get_u16_le();
@@ -66,8 +358,359 @@ impl SensorValues {
         let co2 = bytes.get_u16_le();
         let voc = bytes.get_u16_le();
 
-        return SensorValues{
-            humidity, radon_short, radon_long, temp, atm, co2, voc,
+        SensorValues{
+            version,
+            humidity: Some(humidity),
+            radon_short: Some(radon_short),
+            radon_long: Some(radon_long),
+            temp: Some(temp),
+            atm: Some(atm),
+            co2: Some(co2),
+            voc: Some(voc),
+            battery: None,
+        }
+    }
+
+    fn parse_wave(mut bytes: Bytes) -> SensorValues {
+        let version = bytes.get_u8();
+        // in half percent, as on the Wave Plus
+        let humidity = (bytes.get_u8() as f32) / 2.0;
+        bytes.advance(2);
+        let radon_short = bytes.get_u16_le();
+        let radon_long = bytes.get_u16_le();
+        let temp = bytes.get_u16_le() as f32 / 100.0;
+
+        SensorValues{
+            version,
+            humidity: Some(humidity),
+            radon_short: Some(radon_short),
+            radon_long: Some(radon_long),
+            temp: Some(temp),
+            atm: None,
+            co2: None,
+            voc: None,
+            battery: None,
         }
     }
+
+    fn parse_wave_mini(mut bytes: Bytes) -> SensorValues {
+        let version = bytes.get_u8();
+        bytes.advance(1);
+        // reported in centikelvin
+        let temp = bytes.get_u16_le() as f32 / 100.0 - 273.15;
+        bytes.advance(2);
+        // in hundredths of a percent, as in Airthings' read_wavemini.py
+        let humidity = bytes.get_u16_le() as f32 / 100.0;
+        let voc = bytes.get_u16_le();
+
+        SensorValues{
+            version,
+            humidity: Some(humidity),
+            radon_short: None,
+            radon_long: None,
+            temp: Some(temp),
+            atm: None,
+            co2: None,
+            voc: Some(voc),
+            battery: None,
+        }
+    }
+
+    /// as put together from its characteristics by `SensorModel::value_characteristics`
+    fn parse_wave_gen1(mut bytes: Bytes) -> SensorValues {
+        let radon_short = bytes.get_u16_le();
+        let radon_long = bytes.get_u16_le();
+        // the standard characteristics, signed hundredths of a degree and hundredths of a percent
+        let temp = bytes.get_i16_le() as f32 / 100.0;
+        let humidity = bytes.get_u16_le() as f32 / 100.0;
+
+        SensorValues{
+            // no version is reported
+            version: 0,
+            humidity: Some(humidity),
+            radon_short: Some(radon_short),
+            radon_long: Some(radon_long),
+            temp: Some(temp),
+            atm: None,
+            co2: None,
+            voc: None,
+            battery: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{AirQuality, ParseError, SensorModel, SensorValues};
+
+    #[test]
+    fn serial_is_parsed_from_airthings_manufacturer_data() {
+        let data = |company_id: u16, bytes: &[u8]| -> HashMap<u16, Vec<u8>> {
+            vec![(company_id, bytes.to_vec())].into_iter().collect()
+        };
+
+        let airthings = [super::AIRTHINGS_COMPANY_ID];
+        assert_eq!(super::parse_serial(data(820, &[0x43, 0x2b, 0xa4, 0xae, 0x09, 0x00]), &airthings), Some(2929994563));
+        assert_eq!(super::parse_serial(data(820, &[0x43, 0x2b]), &airthings), None, "too short");
+        assert_eq!(super::parse_serial(data(76, &[0x43, 0x2b, 0xa4, 0xae]), &airthings), None, "another company");
+        assert_eq!(super::parse_serial(data(76, &[0x43, 0x2b, 0xa4, 0xae]), &[820, 76]), Some(2929994563));
+    }
+
+    #[test]
+    fn manufacturer_data_is_logged_as_hex() {
+        let data: HashMap<u16, Vec<u8>> = vec![(820, vec![0x43, 0x2b, 0xa4, 0x0e]), (76, vec![0x02, 0x15])]
+            .into_iter().collect();
+
+        assert_eq!(super::manufacturer_data_hex(&data), "76: 02 15, 820: 43 2b a4 0e");
+        assert_eq!(super::manufacturer_data_hex(&HashMap::new()), "");
+    }
+
+    /// payload bytes as printed by `--dump-raw`
+    fn hex(payload: &str) -> Vec<u8> {
+        payload.split_whitespace().map(|byte| u8::from_str_radix(byte, 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn wave_plus_payload_is_decoded() {
+        let data = hex("01 56 0c 0a 29 00 1f 00 8e 08 11 c5 f2 02 05 00");
+        let values = SensorValues::from_vec(SensorModel::WavePlus, data).unwrap();
+
+        assert_eq!(values.version, 1);
+        assert_eq!(values.humidity, Some(43.0));
+        assert_eq!(values.radon_short, Some(41));
+        assert_eq!(values.radon_long, Some(31));
+        assert_eq!(values.temp, Some(21.9));
+        assert_eq!(values.atm, Some(1008.98));
+        assert_eq!(values.co2, Some(754));
+        assert_eq!(values.voc, Some(5));
+        assert_eq!(values.battery, None, "read from the battery service");
+    }
+
+    #[test]
+    fn wave_payload_is_decoded() {
+        let data = hex("01 5c 00 00 10 00 0e 00 3a 08");
+        let values = SensorValues::from_vec(SensorModel::Wave, data).unwrap();
+
+        assert_eq!(values.humidity, Some(46.0));
+        assert_eq!(values.radon_short, Some(16));
+        assert_eq!(values.radon_long, Some(14));
+        assert_eq!(values.temp, Some(21.06));
+        assert_eq!((values.atm, values.co2, values.voc), (None, None, None));
+    }
+
+    #[test]
+    fn wave_mini_payload_is_decoded() {
+        let data = hex("03 00 46 73 c4 27 18 10 78 00");
+        let values = SensorValues::from_vec(SensorModel::WaveMini, data).unwrap();
+
+        assert_eq!(values.version, 3, "any version is accepted");
+        assert!((values.temp.unwrap() - 21.95).abs() < 0.001, "295.10 K gave {:?}", values.temp);
+        assert_eq!(values.humidity, Some(41.2));
+        assert_eq!(values.voc, Some(120));
+        assert_eq!((values.radon_short, values.atm, values.co2), (None, None, None));
+    }
+
+    #[test]
+    fn wave_gen1_values_are_decoded() {
+        // radon 41 and 31 Bq/m³, 21.90 C, 43.00 %
+        let data = hex("29 00 1f 00 8e 08 cc 10");
+        let values = SensorValues::from_vec(SensorModel::WaveGen1, data).unwrap();
+
+        assert_eq!(values.radon_short, Some(41));
+        assert_eq!(values.radon_long, Some(31));
+        assert_eq!(values.temp, Some(21.9));
+        assert_eq!(values.humidity, Some(43.0));
+        assert_eq!((values.atm, values.co2, values.voc), (None, None, None));
+
+        // -5.00 C
+        let frozen = SensorValues::from_vec(SensorModel::WaveGen1, hex("29 00 1f 00 0c fe cc 10")).unwrap();
+        assert_eq!(frozen.temp, Some(-5.0));
+
+        let missing_humidity = SensorValues::from_vec(SensorModel::WaveGen1, hex("29 00 1f 00 8e 08")).unwrap_err();
+        assert_eq!(missing_humidity, ParseError::TooShort { model: SensorModel::WaveGen1, expected: 8, actual: 6 });
+    }
+
+    #[test]
+    fn models_are_recognized_by_their_values_characteristic() {
+        for model in SensorModel::ALL.iter() {
+            assert_eq!(SensorModel::from_characteristics(&model.value_characteristics()), Some(*model));
+            assert_eq!(model.value_characteristics()[0], model.characteristic_uuid());
+        }
+        let both = [SensorModel::WaveGen1.characteristic_uuid(), SensorModel::Wave.characteristic_uuid()];
+        assert_eq!(SensorModel::from_characteristics(&both), Some(SensorModel::Wave));
+        assert_eq!(SensorModel::from_characteristics(&[]), None);
+    }
+
+    #[test]
+    fn radon_converts_to_pcil() {
+        // radon short 74 Bq/m³, radon long 37 Bq/m³
+        let data = vec![1, 0, 0, 0, 74, 0, 37, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let values = SensorValues::from_vec(SensorModel::WavePlus, data).unwrap();
+
+        assert_eq!(values.radon_short_pcil(), Some(2.0));
+        assert_eq!(values.radon_long_pcil(), Some(1.0));
+        assert_eq!(super::bq_to_pcil(148), 4.0);
+    }
+
+    fn climate(temp: f32, humidity: f32) -> SensorValues {
+        SensorValues {
+            version: 1,
+            humidity: Some(humidity),
+            temp: Some(temp),
+            atm: None,
+            radon_short: None,
+            radon_long: None,
+            co2: None,
+            voc: None,
+            battery: None,
+        }
+    }
+
+    #[test]
+    fn air_quality_levels_follow_airthings_thresholds() {
+        let air = |co2, voc| SensorValues { co2: Some(co2), voc: Some(voc), ..climate(20.0, 50.0) };
+
+        assert_eq!(air(799, 249).co2_level(), Some(AirQuality::Good));
+        assert_eq!(air(800, 250).co2_level(), Some(AirQuality::Fair));
+        assert_eq!(air(1000, 250).co2_level(), Some(AirQuality::Poor));
+        assert_eq!(air(799, 249).voc_level(), Some(AirQuality::Good));
+        assert_eq!(air(800, 1999).voc_level(), Some(AirQuality::Fair));
+        assert_eq!(air(800, 2000).voc_level().map(AirQuality::severity), Some(2));
+        assert_eq!(climate(20.0, 50.0).co2_level(), None);
+    }
+
+    #[test]
+    fn dew_point_matches_reference_values() {
+        let cases = [(20.0, 50.0, 9.26), (25.0, 60.0, 16.69), (0.0, 100.0, 0.0), (-10.0, 80.0, -12.8)];
+        for (temp, humidity, expected) in cases.iter() {
+            let dew_point = climate(*temp, *humidity).dew_point().unwrap();
+            assert!((dew_point - expected).abs() < 0.05, "{}C {}% gave {}, expected {}", temp, humidity, dew_point, expected);
+        }
+        assert_eq!(climate(20.0, 0.0).dew_point(), None);
+    }
+
+    #[test]
+    fn absolute_humidity_matches_reference_values() {
+        let cases = [(20.0, 50.0, 8.65), (30.0, 80.0, 24.27), (0.0, 100.0, 4.85), (20.0, 0.0, 0.0)];
+        for (temp, humidity, expected) in cases.iter() {
+            let absolute = climate(*temp, *humidity).absolute_humidity().unwrap();
+            assert!((absolute - expected).abs() < 0.05, "{}C {}% gave {}, expected {}", temp, humidity, absolute, expected);
+        }
+    }
+
+    #[test]
+    fn implausible_values_are_removed() {
+        // humidity 127.5 %, radon 0xFFFF, temperature 655.35 C
+        let data = vec![1, 0xff, 0, 0, 0xff, 0xff, 40, 0, 0xff, 0xff, 0xa0, 0xc5, 0x58, 0x02, 0x78, 0];
+        let mut values = SensorValues::from_vec(SensorModel::WavePlus, data).unwrap();
+
+        assert_eq!(values.remove_implausible(), vec!["humidity", "temp", "radon_short"]);
+        assert_eq!(values.humidity, None);
+        assert_eq!(values.temp, None);
+        assert_eq!(values.radon_short, None);
+        assert_eq!(values.radon_long, Some(40));
+        assert_eq!(values.atm, Some(1011.84));
+        assert_eq!(values.co2, Some(600));
+        assert_eq!(values.voc, Some(120));
+
+        let mut nan = climate(f32::NAN, 50.0);
+        assert_eq!(nan.remove_implausible(), vec!["temp"]);
+    }
+
+    #[test]
+    fn humidity_is_validated_at_the_edges() {
+        let wave_plus = |byte: u8| {
+            let data = vec![1, byte, 0, 0, 0, 0, 0, 0, 0xd0, 0x07, 0x58, 0xc5, 0x58, 0x02, 0x78, 0];
+            SensorValues::from_vec(SensorModel::WavePlus, data).unwrap()
+        };
+        let wave = |byte: u8| {
+            SensorValues::from_vec(SensorModel::Wave, vec![1, byte, 0, 0, 0, 0, 0, 0, 0xd0, 0x07]).unwrap()
+        };
+        let wave_mini = |hundredths: u16| {
+            let [low, high] = hundredths.to_le_bytes();
+            SensorValues::from_vec(SensorModel::WaveMini, vec![1, 0, 0x46, 0x73, 0, 0, low, high, 0, 0]).unwrap()
+        };
+
+        for values in [wave_plus(0), wave(0), wave_mini(0)].iter_mut() {
+            assert_eq!(values.remove_implausible(), Vec::<&str>::new());
+            assert_eq!(values.humidity, Some(0.0));
+            assert_eq!(values.dew_point(), None, "undefined at 0%");
+        }
+        for values in [wave_plus(200), wave(200), wave_mini(10000)].iter_mut() {
+            assert_eq!(values.remove_implausible(), Vec::<&str>::new());
+            assert_eq!(values.humidity, Some(100.0));
+            assert!((values.dew_point().unwrap() - values.temp.unwrap()).abs() < 0.001, "saturated at 100%");
+        }
+        for values in [wave_plus(201), wave_plus(0xff), wave(0xff), wave_mini(10001), wave_mini(0xffff)].iter_mut() {
+            assert_eq!(values.remove_implausible(), vec!["humidity"], "{:?}", values.humidity);
+            assert_eq!((values.humidity, values.dew_point(), values.absolute_humidity()), (None, None, None));
+        }
+    }
+
+    #[test]
+    fn temperature_converts_to_fahrenheit() {
+        // 20.00 C
+        let data = vec![1, 0, 0, 0, 0, 0, 0, 0, 0xd0, 0x07, 0, 0, 0, 0, 0, 0];
+        let values = SensorValues::from_vec(SensorModel::WavePlus, data).unwrap();
+
+        assert_eq!(values.temp_fahrenheit(), Some(68.0));
+    }
+
+    #[test]
+    fn floats_differ_treats_nan_as_a_value() {
+        let nan = Some(f32::NAN);
+        let cases = [
+            (Some(1.0), Some(1.0), false),
+            (Some(1.0), Some(2.0), true),
+            (nan, nan, false),
+            (nan, Some(1.0), true),
+            (Some(1.0), nan, true),
+            (None, None, false),
+            (None, Some(1.0), true),
+            (Some(1.0), None, true),
+            (None, nan, true),
+            (nan, None, true),
+        ];
+        for (this, other, differ) in cases.iter() {
+            assert_eq!(super::floats_differ(*this, *other), *differ, "{:?} vs {:?}", this, other);
+        }
+    }
+
+    #[test]
+    fn readings_with_nan_compare_by_value() {
+        assert_eq!(climate(f32::NAN, 50.0), climate(f32::NAN, 50.0));
+        assert_ne!(climate(f32::NAN, 50.0), climate(20.0, 50.0));
+        assert_ne!(climate(20.0, 50.0), climate(f32::NAN, 50.0));
+    }
+
+    #[test]
+    fn short_payloads_are_rejected() {
+        for len in [0, 5, 12] {
+            let result = SensorValues::from_vec(SensorModel::WavePlus, vec![0; len]);
+            assert_eq!(result, Err(ParseError::TooShort { model: SensorModel::WavePlus, expected: 16, actual: len }));
+        }
+    }
+
+    #[test]
+    fn short_payloads_are_rejected_for_every_model() {
+        for model in SensorModel::ALL {
+            let result = SensorValues::from_vec(model, vec![1; model.payload_len() - 1]);
+            assert!(result.is_err(), "{:?} should reject a truncated payload", model);
+            assert!(SensorValues::from_vec(model, vec![1; model.payload_len()]).is_ok());
+        }
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let mut data = vec![0; 16];
+        data[0] = 2;
+        let result = SensorValues::from_vec(SensorModel::WavePlus, data);
+        assert_eq!(result, Err(ParseError::UnsupportedVersion { model: SensorModel::WavePlus, version: 2, expected: 1 }));
+
+        let mut data = vec![0; 16];
+        data[0] = 1;
+        assert_eq!(SensorValues::from_vec(SensorModel::WavePlus, data).unwrap().version, 1);
+    }
 }
@@ -0,0 +1,547 @@
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::server::accept::Accept;
+use hyper::server::Builder;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use log::{debug, error, info, warn};
+use prometheus::{Encoder, Registry, TextEncoder, TEXT_FORMAT};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+use crate::config::{AuthConfig, Readiness};
+use crate::openmetrics::{OpenMetricsEncoder, OPENMETRICS_FORMAT};
+use crate::readings::{DeviceReading, Readings};
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::tls::Tls;
+#[cfg(feature = "websocket")]
+use crate::websocket;
+
+/// State behind the `/healthz` liveness and `/readyz` readiness probes.
+#[derive(Clone)]
+pub struct Probes {
+    scanning: Arc<AtomicBool>,
+    readiness: Readiness,
+    configured_serials: Arc<RwLock<Vec<u32>>>,
+}
+
+impl Probes {
+    pub fn new(readiness: Readiness, configured_serials: Vec<u32>) -> Probes {
+        Probes {
+            scanning: Arc::new(AtomicBool::new(false)),
+            readiness,
+            configured_serials: Arc::new(RwLock::new(configured_serials)),
+        }
+    }
+
+    /// after a config reload
+    pub fn set_configured_serials(&self, configured_serials: Vec<u32>) {
+        *self.configured_serials.write().unwrap() = configured_serials;
+    }
+
+    pub fn set_scanning(&self, scanning: bool) {
+        self.scanning.store(scanning, Ordering::Relaxed);
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.scanning.load(Ordering::Relaxed)
+    }
+
+    /// with no configured devices any reading will do
+    fn is_ready(&self, readings: &Readings) -> bool {
+        let read_serials = readings.serials();
+        let configured_serials = self.configured_serials.read().unwrap();
+        if configured_serials.is_empty() {
+            return !read_serials.is_empty();
+        }
+
+        let mut configured = configured_serials.iter();
+        match self.readiness {
+            Readiness::Any => configured.any(|serial| read_serials.contains(serial)),
+            Readiness::All => configured.all(|serial| read_serials.contains(serial)),
+        }
+    }
+}
+
+struct ServerState {
+    registry: Arc<Registry>,
+    readings: Readings,
+    probes: Probes,
+    auth: Option<AuthConfig>,
+    verified: Verified,
+}
+
+/// bcrypt verifications of the credentials, as few as possible and only so many at a time
+struct Verified {
+    /// keys the digests, afresh on every start
+    keys: RandomState,
+    /// digest of the credentials that last passed `auth`, so only new ones pay for bcrypt
+    last: Mutex<Option<u64>>,
+    /// requests beyond are turned away rather than piling up on the blocking threads
+    verifications: Semaphore,
+}
+
+/// bcrypt verifications running at once
+const MAX_VERIFICATIONS: usize = 2;
+
+impl Verified {
+    fn new() -> Verified {
+        Verified { keys: RandomState::new(), last: Mutex::new(None), verifications: Semaphore::new(MAX_VERIFICATIONS) }
+    }
+
+    fn digest(&self, credentials: &str) -> u64 {
+        self.keys.hash_one(credentials)
+    }
+}
+
+/// Serves `/metrics` in the prometheus text format, `/devices` as json and the `/healthz` and `/readyz`
+/// probes until `shutdown` completes, over https when `tls` is given. With `auth` set the readings need
+/// basic auth, the probes stay open for orchestrators.
+pub async fn run<F>(
+    registry: Arc<Registry>,
+    readings: Readings,
+    probes: Probes,
+    addr: SocketAddr,
+    tls: Option<Tls>,
+    auth: Option<AuthConfig>,
+    shutdown: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let state = Arc::new(ServerState { registry, readings, probes, auth, verified: Verified::new() });
+    match tls {
+        #[cfg(feature = "tls")]
+        Some(tls) => {
+            info!("serving metrics on https://{}", addr);
+            serve(hyper::Server::builder(tls::incoming(addr, tls).await?), state, shutdown).await?;
+        }
+        #[cfg(not(feature = "tls"))]
+        Some(tls) => match tls {},
+        None => {
+            info!("serving metrics on http://{}", addr);
+            serve(hyper::Server::try_bind(&addr)?, state, shutdown).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn serve<I, F>(builder: Builder<I>, state: Arc<ServerState>, shutdown: F) -> Result<(), hyper::Error>
+where
+    I: Accept,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn StdError + Send + Sync>>,
+    F: Future<Output = ()>,
+{
+    let service = make_service_fn(move |_: &I::Conn| {
+        let state = Arc::clone(&state);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = Arc::clone(&state);
+                async move { Ok::<_, Infallible>(handle(&state, req).await) }
+            }))
+        }
+    });
+
+    builder
+        .serve(service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+async fn handle(state: &ServerState, req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return text_response(StatusCode::METHOD_NOT_ALLOWED, "405 method not allowed");
+    }
+
+    #[cfg(feature = "websocket")]
+    if req.uri().path() == "/ws" {
+        if !is_authorized(state, &req).await {
+            return unauthorized_response();
+        }
+        return websocket::upgrade(req, &state.readings);
+    }
+
+    let path = req.uri().path();
+    if matches!(path, "/metrics" | "/devices" | "/events") && !is_authorized(state, &req).await {
+        return unauthorized_response();
+    }
+    match path {
+        "/metrics" => metrics_response(state, accepts_gzip(&req), accepts_openmetrics(&req)),
+        "/devices" => devices_response(state),
+        "/events" => events_response(&state.readings),
+        "/healthz" => probe_response(state.probes.is_live()),
+        "/readyz" => probe_response(state.probes.is_ready(&state.readings)),
+        _ => text_response(StatusCode::NOT_FOUND, "404 not found"),
+    }
+}
+
+/// gzipped if the client accepts it, as prometheus does, and in the OpenMetrics format if it asks for that
+fn metrics_response(state: &ServerState, gzip: bool, openmetrics: bool) -> Response<Body> {
+    let families = state.registry.gather();
+    let mut buffer = vec![];
+    let encoded = if openmetrics {
+        OpenMetricsEncoder.encode(&families, &mut buffer).map(|_| OPENMETRICS_FORMAT)
+    } else {
+        TextEncoder::new().encode(&families, &mut buffer).map(|_| TEXT_FORMAT)
+    };
+    let format_type = encoded.expect("write to vec cannot fail");
+
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, format_type)
+        .header(header::VARY, "Accept, Accept-Encoding");
+    if !gzip {
+        return builder.body(Body::from(buffer)).unwrap();
+    }
+
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = gz.write_all(&buffer).and_then(|_| gz.finish()).expect("write to vec cannot fail");
+    builder
+        .header(header::CONTENT_ENCODING, "gzip")
+        .body(Body::from(compressed))
+        .unwrap()
+}
+
+/// gzip listed in `Accept-Encoding`, and not with `q=0`
+fn accepts_gzip(req: &Request<Body>) -> bool {
+    accepted(req, header::ACCEPT_ENCODING).any(|coding| coding.eq_ignore_ascii_case("gzip") || coding == "*")
+}
+
+/// the OpenMetrics format listed in `Accept`, and not with `q=0`, as prometheus 2.5 and later ask
+fn accepts_openmetrics(req: &Request<Body>) -> bool {
+    accepted(req, header::ACCEPT).any(|media_type| media_type.eq_ignore_ascii_case("application/openmetrics-text"))
+}
+
+/// the values listed in an `Accept` style header, without their parameters, leaving out those with `q=0`
+fn accepted(req: &Request<Body>, name: header::HeaderName) -> impl Iterator<Item = &str> {
+    req.headers().get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let value = params.next().unwrap_or_default();
+            let rejected = params.any(|param| {
+                param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            if rejected { None } else { Some(value) }
+        })
+}
+
+fn devices_response(state: &ServerState) -> Response<Body> {
+    match serde_json::to_vec(&state.readings.all()) {
+        Ok(json) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(err) => {
+            error!("failed to serialize device readings: {}", err);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, "500 internal server error")
+        }
+    }
+}
+
+/// comment sent when there are no readings for a while, so proxies don't drop the connection as idle
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Server-sent events, every new reading as a `reading` event with the json served on `/devices`.
+fn events_response(readings: &Readings) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let mut updates = readings.subscribe();
+    tokio::spawn(async move {
+        let mut keep_alive = time::interval(EVENTS_KEEP_ALIVE);
+        keep_alive.tick().await;
+        loop {
+            let event = tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(reading) => match reading_event(&reading) {
+                        Some(event) => event,
+                        None => continue,
+                    },
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("event stream client fell behind, skipped {} readings", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => String::from(": keep-alive\n\n"),
+            };
+            if sender.send_data(event.into()).await.is_err() {
+                debug!("event stream client went away");
+                break;
+            }
+        }
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+fn reading_event(reading: &DeviceReading) -> Option<String> {
+    match serde_json::to_string(reading) {
+        Ok(json) => Some(format!("event: reading\ndata: {}\n\n", json)),
+        Err(err) => {
+            warn!("failed to serialize reading for {}: {}", reading.serial, err);
+            None
+        }
+    }
+}
+
+async fn is_authorized(state: &ServerState, req: &Request<Body>) -> bool {
+    let auth = match &state.auth {
+        Some(auth) => auth,
+        None => return true,
+    };
+
+    let credentials = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+    let (username, password) = match credentials.split_once(':') {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+    if !constant_time_eq(username.as_bytes(), auth.username.as_bytes()) {
+        return false;
+    }
+    let digest = state.verified.digest(&credentials);
+    let known = state.verified.last.lock().unwrap()
+        .is_some_and(|last| constant_time_eq(&last.to_ne_bytes(), &digest.to_ne_bytes()));
+    if known {
+        return true;
+    }
+
+    let _permit = match state.verified.verifications.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            debug!("turning away request, {} passwords are being verified already", MAX_VERIFICATIONS);
+            return false;
+        }
+    };
+    // bcrypt is slow by design, kept off the workers serving requests and querying devices;
+    // the hash is validated on config load, a failure here means a wrong password
+    let (password, hash) = (password.to_string(), auth.password_hash.clone());
+    let verified = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+        .await
+        .unwrap_or(false);
+    if verified {
+        *state.verified.last.lock().unwrap() = Some(digest);
+    }
+    verified
+}
+
+/// compares without giving away through its timing how much of the credentials matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+fn unauthorized_response() -> Response<Body> {
+    let mut response = text_response(StatusCode::UNAUTHORIZED, "401 unauthorized");
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        header::HeaderValue::from_static("Basic realm=\"airthing\", charset=\"UTF-8\""),
+    );
+    response
+}
+
+fn probe_response(ok: bool) -> Response<Body> {
+    if ok {
+        text_response(StatusCode::OK, "ok")
+    } else {
+        text_response(StatusCode::SERVICE_UNAVAILABLE, "503 service unavailable")
+    }
+}
+
+fn text_response(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use flate2::read::GzDecoder;
+    use hyper::body::HttpBody;
+    use hyper::{header, Body, Request};
+    use prometheus::{IntCounter, Registry};
+
+    use crate::config::{AuthConfig, Readiness};
+    use crate::openmetrics::OPENMETRICS_FORMAT;
+    use crate::readings::{DeviceReading, Readings};
+    use crate::sensor::SensorValues;
+    use crate::sink::ReadingSink;
+
+    use super::{
+        accepts_gzip, accepts_openmetrics, events_response, is_authorized, metrics_response, Probes, ServerState,
+        Verified, MAX_VERIFICATIONS,
+    };
+
+    fn read(readings: &Readings, serial: u32) {
+        let values = SensorValues {
+            version: 1,
+            humidity: None,
+            temp: None,
+            atm: None,
+            radon_short: None,
+            radon_long: None,
+            co2: None,
+            voc: None,
+            battery: None,
+        };
+        readings.publish(&DeviceReading::new(serial, &BTreeMap::new(), SystemTime::now(), &values));
+    }
+
+    #[test]
+    fn readiness_waits_for_configured_devices() {
+        let readings = Readings::default();
+        let any = Probes::new(Readiness::Any, vec![1, 2]);
+        let all = Probes::new(Readiness::All, vec![1, 2]);
+
+        read(&readings, 3);
+        assert!(!any.is_ready(&readings));
+
+        read(&readings, 1);
+        assert!(any.is_ready(&readings));
+        assert!(!all.is_ready(&readings));
+
+        read(&readings, 2);
+        assert!(all.is_ready(&readings));
+    }
+
+    #[test]
+    fn gzip_is_negotiated() {
+        let request = |accept_encoding: &str| {
+            Request::get("/metrics").header(header::ACCEPT_ENCODING, accept_encoding).body(Body::empty()).unwrap()
+        };
+
+        assert!(accepts_gzip(&request("gzip")));
+        assert!(accepts_gzip(&request("deflate, GZIP;q=0.5")));
+        assert!(!accepts_gzip(&request("gzip;q=0, identity")));
+        assert!(!accepts_gzip(&request("br")));
+        assert!(!accepts_gzip(&Request::get("/metrics").body(Body::empty()).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_gzipped_on_request() {
+        let registry = Registry::new();
+        let counter = IntCounter::new("reads_total", "reads").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let state = ServerState {
+            registry: Arc::new(registry),
+            readings: Readings::default(),
+            probes: Probes::new(Readiness::Any, vec![]),
+            auth: None,
+            verified: Verified::new(),
+        };
+
+        let response = metrics_response(&state, true, false);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut text = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut text).unwrap();
+        assert!(text.contains("reads_total 1"));
+
+        let response = metrics_response(&state, false, false);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+
+        let response = metrics_response(&state, false, true);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], OPENMETRICS_FORMAT);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().ends_with("reads_total 1\n# EOF\n"));
+    }
+
+    #[test]
+    fn openmetrics_is_negotiated() {
+        let request = |accept: &str| {
+            Request::get("/metrics").header(header::ACCEPT, accept).body(Body::empty()).unwrap()
+        };
+
+        // as sent by prometheus
+        assert!(accepts_openmetrics(&request("application/openmetrics-text;version=1.0.0,\
+            application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1")));
+        assert!(!accepts_openmetrics(&request("text/plain;version=0.0.4")));
+        assert!(!accepts_openmetrics(&request("application/openmetrics-text;q=0, text/plain")));
+    }
+
+    #[tokio::test]
+    async fn readings_are_streamed_as_events() {
+        let readings = Readings::default();
+        let mut body = events_response(&readings).into_body();
+
+        read(&readings, 1);
+        let event = body.data().await.unwrap().unwrap();
+
+        let event = std::str::from_utf8(&event).unwrap();
+        assert!(event.starts_with("event: reading\ndata: {\"serial\":1,"), "{}", event);
+        assert!(event.ends_with("}\n\n"));
+    }
+
+    #[tokio::test]
+    async fn readings_need_matching_basic_auth() {
+        let state = ServerState {
+            registry: Arc::new(Registry::new()),
+            readings: Readings::default(),
+            probes: Probes::new(Readiness::Any, vec![]),
+            auth: Some(AuthConfig {
+                username: String::from("prometheus"),
+                password_hash: bcrypt::hash("secret", 4).unwrap(),
+            }),
+            verified: Verified::new(),
+        };
+        let request = |credentials: Option<&str>| {
+            let mut builder = Request::get("/metrics");
+            if let Some(credentials) = credentials {
+                builder = builder.header(header::AUTHORIZATION, format!("Basic {}", STANDARD.encode(credentials)));
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        assert!(!is_authorized(&state, &request(Some("prometheus:wrong"))).await);
+        assert_eq!(*state.verified.last.lock().unwrap(), None);
+        assert!(is_authorized(&state, &request(Some("prometheus:secret"))).await);
+        let digest = state.verified.digest("prometheus:secret");
+        assert_eq!(*state.verified.last.lock().unwrap(), Some(digest), "verified once, kept as a digest");
+        assert!(is_authorized(&state, &request(Some("prometheus:secret"))).await);
+        assert!(!is_authorized(&state, &request(Some("prometheus:wrong"))).await);
+        assert!(!is_authorized(&state, &request(Some("grafana:secret"))).await);
+        assert!(!is_authorized(&state, &request(None)).await);
+
+        let _busy = state.verified.verifications.acquire_many(MAX_VERIFICATIONS as u32).await.unwrap();
+        assert!(is_authorized(&state, &request(Some("prometheus:secret"))).await, "known credentials need no bcrypt");
+        *state.verified.last.lock().unwrap() = None;
+        assert!(!is_authorized(&state, &request(Some("prometheus:secret"))).await, "turned away while saturated");
+    }
+}
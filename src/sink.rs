@@ -0,0 +1,6 @@
+use crate::readings::DeviceReading;
+
+/// Receives every reading as soon as it is read from a device.
+pub trait ReadingSink: Send + Sync {
+    fn publish(&self, reading: &DeviceReading);
+}
@@ -0,0 +1,150 @@
+//! Where readings come from: Bluetooth devices, or synthesized ones in mock mode and tests.
+
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use thiserror::Error;
+
+use crate::sensor::{SensorModel, SensorValues};
+
+/// A device seen in the latest discovery pass.
+#[derive(Debug, Clone)]
+pub struct Advertisement {
+    pub serial: u32,
+    /// signal strength in dBm, if reported
+    pub rssi: Option<i16>,
+    /// name of the adapter that heard it, e.g. `hci0`, if there is one
+    pub adapter: Option<String>,
+}
+
+/// Result of a discovery pass.
+#[derive(Debug, Clone, Default)]
+pub struct Scan {
+    pub advertisements: Vec<Advertisement>,
+    /// all peripherals in range, with an Airthings serial or not
+    pub peripherals: usize,
+}
+
+/// What a device reports about itself, fixed until a firmware update.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub model: Option<String>,
+    pub firmware: Option<String>,
+}
+
+#[async_trait(?Send)]
+pub trait SensorSource {
+    /// devices currently in range
+    async fn scan(&self) -> Result<Scan>;
+    /// Bluetooth adapters scanned on
+    fn adapters(&self) -> usize;
+    /// reads a device seen in the latest scan
+    async fn read(&self, serial: u32) -> Result<SensorValues, QueryError>;
+    /// the sensor characteristic as read, before parsing
+    async fn read_raw(&self, serial: u32) -> Result<(SensorModel, Vec<u8>)>;
+    /// model and firmware of a device just read, only queried from the device the first time
+    async fn device_info(&self, serial: u32) -> Result<DeviceInfo>;
+    async fn disconnect(&self, serial: u32) -> Result<()>;
+    /// keeps a device just read connected and has it push new values, `false` if its sensor characteristic
+    /// does not notify
+    async fn subscribe(&self, serial: u32) -> Result<bool>;
+    /// subscribed devices that are still connected, they need not be read
+    fn subscribed(&self) -> Vec<u32>;
+    /// waits for the next values pushed by a subscribed device, never returns without subscriptions
+    async fn next_notification(&self) -> (u32, SensorValues);
+    /// stops and starts discovery again, for when the adapter stopped reporting devices
+    async fn restart_scan(&self) -> Result<()>;
+    /// drops all connections, on shutdown
+    async fn disconnect_all(&self);
+}
+
+/// A device that stopped answering, the cause of query errors alongside their `FailureReason`.
+#[derive(Debug, Error)]
+#[error("no answer within {0:?}")]
+pub struct Timeout(pub Duration);
+
+/// A failed read, with the query step that failed and what caused it.
+#[derive(Debug, Error)]
+#[error("{reason}: {cause:#}")]
+pub struct QueryError {
+    pub reason: FailureReason,
+    pub cause: anyhow::Error,
+}
+
+/// Fails a query at a step, as `anyhow::Context` does with a message.
+pub trait WithReason<T> {
+    fn reason(self, reason: FailureReason) -> Result<T, QueryError>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithReason<T> for Result<T, E> {
+    fn reason(self, reason: FailureReason) -> Result<T, QueryError> {
+        self.map_err(|cause| QueryError { reason, cause: cause.into() })
+    }
+}
+
+/// The query step that failed.
+#[derive(Debug, Clone, Copy)]
+pub enum FailureReason {
+    Connect,
+    Discover,
+    /// the device does not expose the sensor values characteristics of any model we know
+    Characteristic,
+    Read,
+    Parse,
+}
+
+impl FailureReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureReason::Connect => "connect",
+            FailureReason::Discover => "discover",
+            FailureReason::Characteristic => "characteristic",
+            FailureReason::Read => "read",
+            FailureReason::Parse => "parse",
+        }
+    }
+
+    /// the device answered, with something that will not change on another attempt
+    pub fn is_final(&self) -> bool {
+        matches!(self, FailureReason::Characteristic | FailureReason::Parse)
+    }
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureReason::Connect => write!(f, "Failed to connect to a peripheral"),
+            FailureReason::Discover => write!(f, "Failed to discover services"),
+            FailureReason::Characteristic => write!(f, "Failed to find the sensor values characteristic"),
+            FailureReason::Read => write!(f, "Failed to read data from characteristic"),
+            FailureReason::Parse => write!(f, "Failed to parse sensor values"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FailureReason, WithReason};
+    use crate::sensor::{ParseError, SensorModel};
+
+    #[test]
+    fn failure_reason_and_cause_are_both_recoverable() {
+        let cause = ParseError::TooShort { model: SensorModel::WavePlus, expected: 16, actual: 3 };
+        let err = Err::<(), _>(cause).reason(FailureReason::Parse).unwrap_err();
+
+        assert_eq!(err.reason.as_str(), "parse");
+        assert!(err.cause.downcast_ref::<ParseError>().is_some());
+        assert_eq!(err.to_string(),
+            "Failed to parse sensor values: WavePlus payload too short: expected 16 bytes, got 3");
+    }
+
+    #[test]
+    fn only_answers_that_will_not_change_are_final() {
+        assert!(FailureReason::Parse.is_final());
+        assert!(FailureReason::Characteristic.is_final());
+        assert!(!FailureReason::Connect.is_final());
+        assert!(!FailureReason::Read.is_final());
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use rusqlite::{params, Connection};
+
+use crate::config::SqliteConfig;
+use crate::readings::DeviceReading;
+use crate::sink::ReadingSink;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS readings (
+        time INTEGER NOT NULL,
+        serial INTEGER NOT NULL,
+        labels TEXT NOT NULL,
+        humidity REAL,
+        temperature REAL,
+        atm_pressure REAL,
+        radon_short INTEGER,
+        radon_long INTEGER,
+        co2 INTEGER,
+        voc INTEGER,
+        battery INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS readings_serial_time ON readings (serial, time);
+";
+
+struct SqliteSink {
+    path: String,
+    connection: Mutex<Connection>,
+}
+
+/// Appends every reading to the `readings` table of a SQLite database, created along with the table if missing.
+/// `time` is the unix timestamp of the read, `labels` a json object, values a device does not measure are null.
+pub fn new_sqlite_sink(config: &SqliteConfig) -> rusqlite::Result<Box<dyn ReadingSink>> {
+    let connection = Connection::open(&config.path)?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(Box::new(SqliteSink { path: config.path.clone(), connection: Mutex::new(connection) }))
+}
+
+impl ReadingSink for SqliteSink {
+    fn publish(&self, reading: &DeviceReading) {
+        let labels = serde_json::to_string(&reading.labels).unwrap_or_default();
+        let values = &reading.values;
+        let inserted = self.connection.lock().unwrap().execute(
+            "INSERT INTO readings (time, serial, labels, humidity, temperature, atm_pressure, radon_short, radon_long, \
+                co2, voc, battery) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                reading.last_seen as i64,
+                reading.serial,
+                labels,
+                values.humidity.filter(|value| value.is_finite()),
+                values.temp.filter(|value| value.is_finite()),
+                values.atm.filter(|value| value.is_finite()),
+                values.radon_short,
+                values.radon_long,
+                values.co2,
+                values.voc,
+                values.battery,
+            ],
+        );
+        match inserted {
+            Ok(_) => debug!("wrote reading for {} to {}", reading.serial, self.path),
+            Err(err) => warn!("failed to write reading for {} to {}: {}", reading.serial, self.path, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rusqlite::Connection;
+
+    use super::new_sqlite_sink;
+    use crate::config::SqliteConfig;
+    use crate::readings::DeviceReading;
+
+    #[test]
+    fn readings_are_appended() {
+        let path = std::env::temp_dir().join(format!("airthing-sqlite-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let sink = new_sqlite_sink(&SqliteConfig { path: path.to_string() }).unwrap();
+        let mut reading = DeviceReading::fixture(2930025667, &[("room", "Study")]);
+        sink.publish(&reading);
+        reading.last_seen += 300;
+        sink.publish(&reading);
+        drop(sink);
+
+        let connection = Connection::open(path).unwrap();
+        let rows: Vec<(i64, String, Option<f64>, Option<u16>)> = connection
+            .prepare("SELECT time, labels, temperature, voc FROM readings WHERE serial = 2930025667 ORDER BY time")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(connection);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        let labels = String::from("{\"room\":\"Study\",\"serial\":\"2930025667\"}");
+        assert_eq!(rows[0], (1600000000, labels, Some(21.25), None));
+        assert_eq!(rows[1].0, 1600000300);
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A learned expected interval, as unix timestamps in milliseconds since `Instant` can't be persisted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedInterval {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl SavedInterval {
+    pub fn from_instants(now: Instant, wall_now: SystemTime, interval: (Instant, Instant)) -> SavedInterval {
+        SavedInterval {
+            start: to_unix_millis(now, wall_now, interval.0),
+            end: to_unix_millis(now, wall_now, interval.1),
+        }
+    }
+
+    /// `None` if the interval can't be represented as an `Instant`, e.g. it predates boot
+    pub fn to_instants(self, now: Instant, wall_now: SystemTime) -> Option<(Instant, Instant)> {
+        Some((
+            to_instant(now, wall_now, self.start)?,
+            to_instant(now, wall_now, self.end)?,
+        ))
+    }
+}
+
+fn to_unix_millis(now: Instant, wall_now: SystemTime, instant: Instant) -> u64 {
+    let wall = if instant >= now {
+        wall_now + (instant - now)
+    } else {
+        wall_now - (now - instant)
+    };
+    wall.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+fn to_instant(now: Instant, wall_now: SystemTime, millis: u64) -> Option<Instant> {
+    let wall = UNIX_EPOCH + Duration::from_millis(millis);
+    match wall.duration_since(wall_now) {
+        Ok(ahead) => now.checked_add(ahead),
+        Err(err) => now.checked_sub(err.duration()),
+    }
+}
+
+pub fn load_state(path: &str) -> Result<HashMap<u32, SavedInterval>> {
+    let state_str = fs::read_to_string(path)
+        .with_context(|| format!("failed to read state file {}", path))?;
+    serde_json::from_str(&state_str)
+        .with_context(|| format!("failed to parse state file {}", path))
+}
+
+pub fn save_state(path: &str, intervals: &HashMap<u32, SavedInterval>) -> Result<()> {
+    let state_str = serde_json::to_string_pretty(intervals)?;
+    fs::write(path, state_str)
+        .with_context(|| format!("failed to write state file {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::SavedInterval;
+
+    #[test]
+    fn intervals_survive_a_round_trip() {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let interval = (now + Duration::from_secs(10), now + Duration::from_secs(70));
+
+        let saved = SavedInterval::from_instants(now, wall_now, interval);
+        let restored = saved.to_instants(now, wall_now).unwrap();
+
+        assert!(restored.0 <= interval.0 && interval.0 - restored.0 < Duration::from_millis(1));
+        assert!(restored.1 <= interval.1 && interval.1 - restored.1 < Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+
+use log::warn;
+
+use crate::readings::DeviceReading;
+use crate::sink::ReadingSink;
+
+struct TextfileSink {
+    path: String,
+    namespace: String,
+    timestamps: bool,
+    /// latest reading per device, the file is rewritten with all of them on every reading
+    latest: Mutex<BTreeMap<u32, DeviceReading>>,
+}
+
+/// Keeps the latest reading of every device in a file in the prometheus text format, for node_exporter's
+/// textfile collector. With `timestamps` every sample carries the time it was read at.
+pub fn new_textfile_sink(path: &str, namespace: &str, timestamps: bool) -> Box<dyn ReadingSink> {
+    Box::new(TextfileSink {
+        path: path.to_string(),
+        namespace: namespace.to_string(),
+        timestamps,
+        latest: Mutex::new(BTreeMap::new()),
+    })
+}
+
+impl ReadingSink for TextfileSink {
+    fn publish(&self, reading: &DeviceReading) {
+        let mut latest = self.latest.lock().unwrap();
+        latest.insert(reading.serial, reading.clone());
+        let text = exposition(&self.namespace, latest.values(), self.timestamps);
+        if let Err(err) = write_atomically(&self.path, &text) {
+            warn!("failed to write readings to {}: {}", self.path, err);
+        }
+    }
+}
+
+/// name, help and value of every sample a reading has
+fn samples(reading: &DeviceReading) -> [(&'static str, &'static str, Option<f32>); 8] {
+    let values = &reading.values;
+    [
+        ("relative_humidity_percent", "in rel%", values.humidity),
+        ("temperature_celsius", "air temperature, in C", values.temp),
+        ("atmospheric_pressure_mbar", "atmospheric pressure, in mbar", values.atm),
+        ("radon_short_bq_per_m3", "24 hour average, in Bq/m3", values.radon_short.map(f32::from)),
+        ("radon_long_bq_per_m3", "average since measuring started, in Bq/m3", values.radon_long.map(f32::from)),
+        ("co2_ppm", "in ppm", values.co2.map(f32::from)),
+        ("voc_ppb", "in ppb", values.voc.map(f32::from)),
+        ("battery_percent", "battery level, in %", values.battery.map(f32::from)),
+    ]
+}
+
+/// one family per measurement, with a sample per device that measures it
+fn exposition<'a>(namespace: &str, readings: impl Iterator<Item = &'a DeviceReading>, timestamps: bool) -> String {
+    let mut families: BTreeMap<&str, (&str, Vec<String>)> = BTreeMap::new();
+    for reading in readings {
+        for (name, help, value) in samples(reading).iter() {
+            let value = match value.filter(|value| value.is_finite()) {
+                Some(value) => value,
+                None => continue,
+            };
+            let labels: Vec<String> = reading.labels.iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                .collect();
+            let mut sample = format!("{{{}}} {}", labels.join(","), value);
+            if timestamps {
+                // in milliseconds
+                let _ = write!(sample, " {}", reading.last_seen * 1000);
+            }
+            families.entry(name).or_insert_with(|| (help, Vec::new())).1.push(sample);
+        }
+    }
+
+    let prefix = if namespace.is_empty() { String::new() } else { format!("{}_", namespace) };
+    let mut text = String::new();
+    for (name, (help, samples)) in families {
+        let _ = writeln!(text, "# HELP {}{} {}", prefix, name, help);
+        let _ = writeln!(text, "# TYPE {}{} gauge", prefix, name);
+        for sample in samples {
+            let _ = writeln!(text, "{}{}{}", prefix, name, sample);
+        }
+    }
+    text
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// the collector never sees a half written file
+fn write_atomically(path: &str, text: &str) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::new_textfile_sink;
+    use crate::readings::DeviceReading;
+
+    fn reading(serial: u32, room: &str, co2: u16) -> DeviceReading {
+        let mut reading = DeviceReading::fixture(serial, &[("room", room)]);
+        // humidity and co2 are enough to tell the samples apart
+        reading.values.temp = None;
+        reading.values.radon_short = None;
+        reading.values.co2 = Some(co2);
+        reading
+    }
+
+    #[test]
+    fn latest_readings_are_written_with_timestamps() {
+        let path = std::env::temp_dir().join(format!("airthing-textfile-{}.prom", std::process::id()));
+        let path = path.to_str().unwrap();
+        let sink = new_textfile_sink(path, "airthing", true);
+
+        sink.publish(&reading(2, "Study \"upstairs\"", 600));
+        sink.publish(&reading(1, "Bedroom", 700));
+        sink.publish(&reading(2, "Study \"upstairs\"", 650));
+
+        let text = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(text, "# HELP airthing_co2_ppm in ppm\n\
+            # TYPE airthing_co2_ppm gauge\n\
+            airthing_co2_ppm{room=\"Bedroom\",serial=\"1\"} 700 1600000000000\n\
+            airthing_co2_ppm{room=\"Study \\\"upstairs\\\"\",serial=\"2\"} 650 1600000000000\n\
+            # HELP airthing_relative_humidity_percent in rel%\n\
+            # TYPE airthing_relative_humidity_percent gauge\n\
+            airthing_relative_humidity_percent{room=\"Bedroom\",serial=\"1\"} 45.5 1600000000000\n\
+            airthing_relative_humidity_percent{room=\"Study \\\"upstairs\\\"\",serial=\"2\"} 45.5 1600000000000\n");
+    }
+}
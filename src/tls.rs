@@ -0,0 +1,89 @@
+//! HTTPS for the metrics server, only available when built with `--features tls`.
+
+use anyhow::Result;
+
+use crate::config::TlsConfig;
+
+#[cfg(feature = "tls")]
+pub use self::rustls::{incoming, load, Tls};
+
+/// Stands in for the acceptor when built without tls, so `[tls]` can only ever fail to load.
+#[cfg(not(feature = "tls"))]
+pub enum Tls {}
+
+#[cfg(not(feature = "tls"))]
+pub fn load(_config: &TlsConfig) -> Result<Tls> {
+    Err(anyhow::anyhow!("[tls] is configured, but airthing was built without the tls feature"))
+}
+
+#[cfg(feature = "tls")]
+mod rustls {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use anyhow::Context;
+    use futures::stream;
+    use hyper::server::accept::{self, Accept};
+    use log::debug;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::server::TlsStream;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::{Result, TlsConfig};
+
+    pub type Tls = TlsAcceptor;
+
+    /// reads the certificate chain and key up front, so a broken `[tls]` section stops startup
+    pub fn load(config: &TlsConfig) -> Result<Tls> {
+        let certs = CertificateDer::pem_file_iter(&config.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("failed to load tls certificate {}", config.cert_path))?;
+        let key = PrivateKeyDer::from_pem_file(&config.key_path)
+            .with_context(|| format!("failed to load tls key {}", config.key_path))?;
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .with_context(|| format!("tls key {} does not fit certificate {}", config.key_path, config.cert_path))?;
+
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+
+    /// accepts connections on `addr`, handshakes run in their own tasks so a slow client can't stall others
+    pub async fn incoming(addr: SocketAddr, tls: Tls) -> io::Result<impl Accept<Conn = TlsStream<TcpStream>, Error = io::Error>> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        debug!("failed to accept connection: {:?}", err);
+                        continue;
+                    }
+                };
+
+                let tls = tls.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tls.accept(stream).await {
+                        Ok(stream) => {
+                            let _ = tx.send(stream).await;
+                        }
+                        Err(err) => debug!("tls handshake with {} failed: {:?}", peer, err),
+                    }
+                });
+            }
+        });
+
+        let streams = stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|stream| (Ok(stream), rx))
+        });
+        Ok(accept::from_stream(streams))
+    }
+}
@@ -0,0 +1,154 @@
+//! Live readings pushed over a websocket on `/ws`, only available when built with `--features websocket`.
+
+use futures::{SinkExt, StreamExt};
+use hyper::{header, Body, Request, Response, StatusCode};
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::readings::{DeviceReading, Readings};
+
+/// Accepts the websocket handshake and sends every new reading as a json text message, as served on `/devices`.
+pub fn upgrade(mut req: Request<Body>, readings: &Readings) -> Response<Body> {
+    let key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_upgrade(&req) => derive_accept_key(key.as_bytes()),
+        _ => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from("400 websocket upgrade expected"))
+                .unwrap();
+        }
+    };
+
+    let updates = readings.subscribe();
+    tokio::spawn(async move {
+        let upgraded = match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                warn!("websocket upgrade failed: {}", err);
+                return;
+            }
+        };
+        serve(WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await, updates).await;
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, key)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Sends the updates until the client closes the socket. What the client sends is read and dropped, reading it
+/// has the library answer pings and the close.
+async fn serve<S>(mut socket: WebSocketStream<S>, mut updates: Receiver<DeviceReading>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let reading = tokio::select! {
+            update = updates.recv() => match update {
+                Ok(reading) => reading,
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("websocket client fell behind, skipped {} readings", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.next() => match message {
+                Some(Ok(Message::Close(_))) => {
+                    // sends the close queued in reply
+                    let _ = socket.flush().await;
+                    debug!("websocket client closed the connection");
+                    break;
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => {
+                    debug!("websocket client went away: {}", err);
+                    break;
+                }
+                None => break,
+            },
+        };
+        let json = match serde_json::to_string(&reading) {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("failed to serialize reading for {}: {}", reading.serial, err);
+                continue;
+            }
+        };
+        if let Err(err) = socket.send(Message::text(json)).await {
+            debug!("websocket client went away: {}", err);
+            break;
+        }
+    }
+}
+
+fn is_upgrade(req: &Request<Body>) -> bool {
+    let has = |name, value: &str| req.headers().get_all(name).iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case(value));
+    has(header::CONNECTION, "upgrade") && has(header::UPGRADE, "websocket")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use hyper::{header, Body, Request, StatusCode};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    use super::{serve, upgrade};
+    use crate::readings::{DeviceReading, Readings};
+
+    #[tokio::test]
+    async fn handshake_is_answered() {
+        let request = |connection: &str| {
+            Request::get("/ws")
+                .header(header::CONNECTION, connection)
+                .header(header::UPGRADE, "websocket")
+                .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+                .header(header::SEC_WEBSOCKET_VERSION, "13")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = upgrade(request("keep-alive, Upgrade"), &Readings::default());
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        // the example from RFC 6455
+        assert_eq!(response.headers()[header::SEC_WEBSOCKET_ACCEPT], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        assert_eq!(upgrade(request("keep-alive"), &Readings::default()).status(), StatusCode::BAD_REQUEST);
+    }
+    #[tokio::test]
+    async fn pings_and_close_are_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        let (updates, subscribed) = broadcast::channel(8);
+        let server = WebSocketStream::from_raw_socket(accepted.unwrap().0, Role::Server, None).await;
+        let served = tokio::spawn(serve(server, subscribed));
+        let mut client = WebSocketStream::from_raw_socket(client.unwrap(), Role::Client, None).await;
+
+        client.send(Message::Ping(b"alive?".to_vec())).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::Pong(b"alive?".to_vec()));
+        updates.send(DeviceReading::fixture(1, &[])).unwrap();
+        assert!(client.next().await.unwrap().unwrap().is_text());
+
+        client.send(Message::Close(None)).await.unwrap();
+        assert!(client.next().await.unwrap().unwrap().is_close());
+        served.await.unwrap();
+    }
+}